@@ -0,0 +1,120 @@
+use crate::{Todo, ID};
+use reqwest::blocking::{Client, Response};
+use reqwest::StatusCode;
+use std::time::Duration;
+
+#[derive(Deserialize)]
+struct Created {
+    id: ID,
+}
+
+/// A blocking client for the todo API.
+pub struct TodoClient {
+    base_url: String,
+    http: Client,
+}
+
+/// Configures a [`TodoClient`] before it is built.
+pub struct TodoClientBuilder {
+    base_url: String,
+    timeout: Option<Duration>,
+}
+
+impl TodoClientBuilder {
+    /// Gives up on requests that take longer than `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> TodoClientBuilder {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    pub fn build(self) -> reqwest::Result<TodoClient> {
+        // The server drops idle connections between requests, so reusing a
+        // pooled one fails with an incomplete message.
+        let mut http = Client::builder().pool_max_idle_per_host(0);
+        if let Some(timeout) = self.timeout {
+            http = http.timeout(timeout);
+        }
+
+        Ok(TodoClient {
+            base_url: self.base_url.trim_end_matches('/').to_string(),
+            http: http.build()?,
+        })
+    }
+}
+
+impl TodoClient {
+    /// A client for the server at `base_url`, e.g. `http://localhost:8000`.
+    pub fn new<T: Into<String>>(base_url: T) -> reqwest::Result<TodoClient> {
+        TodoClient::builder(base_url).build()
+    }
+
+    pub fn builder<T: Into<String>>(base_url: T) -> TodoClientBuilder {
+        TodoClientBuilder {
+            base_url: base_url.into(),
+            timeout: None,
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    pub fn list(&self) -> reqwest::Result<Vec<Todo>> {
+        self.http
+            .get(self.url("/"))
+            .header("Accept", "application/json")
+            .send()?
+            .error_for_status()?
+            .json()
+    }
+
+    /// Fetches a todo, or `None` if no todo has that id.
+    pub fn get(&self, id: ID) -> reqwest::Result<Option<Todo>> {
+        let res = self
+            .http
+            .get(self.url(&format!("/{}", id)))
+            .header("Accept", "application/json")
+            .send()?;
+        found(res)?.map(Response::json).transpose()
+    }
+
+    /// Creates a todo, returning the id it was stored under.
+    pub fn create(&self, todo: &Todo) -> reqwest::Result<ID> {
+        let created: Created = self
+            .http
+            .post(self.url("/"))
+            .json(todo)
+            .send()?
+            .error_for_status()?
+            .json()?;
+        Ok(created.id)
+    }
+
+    /// Replaces a todo, returning `false` if no todo has that id.
+    pub fn update(&self, id: ID, todo: &Todo) -> reqwest::Result<bool> {
+        let res = self
+            .http
+            .put(self.url(&format!("/{}", id)))
+            .json(todo)
+            .send()?;
+        Ok(found(res)?.is_some())
+    }
+
+    pub fn delete(&self, id: ID) -> reqwest::Result<()> {
+        self.http
+            .delete(self.url(&format!("/{}", id)))
+            .header("Content-Type", "application/json")
+            .send()?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Treats `404` as a missing todo rather than an error.
+fn found(res: Response) -> reqwest::Result<Option<Response>> {
+    if res.status() == StatusCode::NOT_FOUND {
+        Ok(None)
+    } else {
+        res.error_for_status().map(Some)
+    }
+}
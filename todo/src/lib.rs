@@ -0,0 +1,111 @@
+#[macro_use]
+extern crate serde_derive;
+
+use chrono::{DateTime, Utc};
+use rocket::http::RawStr;
+use rocket::request::FromFormValue;
+
+mod client;
+
+pub use client::{TodoClient, TodoClientBuilder};
+
+pub type ID = usize;
+
+/// How urgent a todo is; higher is more urgent. The server decides which
+/// values are in range (1 to 5 unless configured otherwise).
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Priority(pub usize);
+
+impl<'v> FromFormValue<'v> for Priority {
+    type Error = &'v RawStr;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<Priority, &'v RawStr> {
+        form_value
+            .parse::<usize>()
+            .map(Priority)
+            .map_err(|_| form_value)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Todo {
+    /// Left as `0` to have the server assign an id on creation.
+    #[serde(default)]
+    pub id: ID,
+    pub priority: Priority,
+    pub title: String,
+    #[serde(default)]
+    pub completed: bool,
+    /// Maintained by the server: when the todo was last completed.
+    #[serde(default)]
+    pub completed_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub due_date: Option<DateTime<Utc>>,
+    /// Maintained by the server; any value sent by a client is replaced.
+    #[serde(default)]
+    pub created_at: DateTime<Utc>,
+    /// Maintained by the server; any value sent by a client is replaced.
+    #[serde(default)]
+    pub updated_at: DateTime<Utc>,
+    /// Starts at `1` and is bumped by the server on every change.
+    #[serde(default)]
+    pub version: u64,
+    /// A `#RRGGBB` color for clients to theme the todo with.
+    #[serde(default)]
+    pub color: Option<String>,
+    /// Who the todo is assigned to, if anyone.
+    #[serde(default)]
+    pub owner: Option<String>,
+    /// The todo this is a sub-task of.
+    #[serde(default)]
+    pub parent_id: Option<ID>,
+    /// Arbitrary client data, stored and returned as given.
+    #[serde(default)]
+    pub metadata: serde_json::Map<String, serde_json::Value>,
+    /// When the todo was archived. Archived todos are left out of listings
+    /// until restored or purged.
+    #[serde(default)]
+    pub archived_at: Option<DateTime<Utc>>,
+    /// Breaks ties between todos of equal priority; heavier sorts first.
+    #[serde(default)]
+    pub weight: f64,
+    /// A client-defined tally, such as how often the todo was snoozed.
+    #[serde(default)]
+    pub count: u64,
+    /// Where the todo goes in a hand-arranged list; todos without one come
+    /// after those with one.
+    #[serde(default)]
+    pub position: Option<usize>,
+    /// Free-form remarks, oldest first.
+    #[serde(default)]
+    pub notes: Vec<String>,
+}
+
+impl Todo {
+    /// A pending, untagged todo.
+    pub fn new<T: Into<String>>(id: ID, title: T, priority: Priority) -> Todo {
+        Todo {
+            id,
+            priority,
+            title: title.into(),
+            completed: false,
+            completed_at: None,
+            tags: Vec::new(),
+            due_date: None,
+            created_at: DateTime::default(),
+            updated_at: DateTime::default(),
+            version: 0,
+            color: None,
+            owner: None,
+            parent_id: None,
+            metadata: serde_json::Map::new(),
+            archived_at: None,
+            weight: 0.0,
+            count: 0,
+            position: None,
+            notes: Vec::new(),
+        }
+    }
+}
@@ -6,190 +6,7760 @@ extern crate rocket_contrib;
 #[macro_use]
 extern crate serde_derive;
 
-use rocket::http::RawStr;
-use rocket::request::FromFormValue;
-use rocket::State;
+mod wal;
+
+use chrono::{DateTime, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use flate2::Compression;
+use jsonwebtoken::{DecodingKey, Validation};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use rocket::config::Config;
+use rocket::data::{self, Data, FromDataSimple};
+use rocket::fairing::{AdHoc, Fairing, Info, Kind};
+use rocket::handler::{self, Handler};
+use rocket::http::uri::{Origin, Segments};
+use rocket::http::{ContentType, Header, Method, RawStr, Status, StatusClass};
+use rocket::request::{self, FromFormValue, FromParam, FromRequest, Request};
+use rocket::response::{self, content, status, Redirect, Responder, Response};
+use rocket::{Outcome, Route, State};
 use rocket_contrib::json::{Json, JsonValue};
-use std::collections::HashMap;
-use std::ops::Deref;
-use std::sync::Mutex;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::cmp;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::io::{self, Cursor, Read, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Mutex, PoisonError, RwLock, RwLockWriteGuard, TryLockError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use thiserror::Error;
+use todo::{Priority, Todo, ID};
+
+type TodoRepository = RwLock<HashMap<ID, Todo>>;
+
+/// The id handed to the next todo created without one, or zero once
+/// `usize::MAX` has been used and no ids are left.
+struct NextId(AtomicUsize);
+
+/// The id after `id`, or zero when there is none.
+fn following(id: ID) -> ID {
+    id.checked_add(1).unwrap_or(0)
+}
+
+fn out_of_ids() -> ApiError {
+    error(Status::InsufficientStorage, "No ids are left to assign.")
+}
+
+impl NextId {
+    /// Starts numbering after the largest id in use.
+    fn after(largest: Option<ID>) -> NextId {
+        NextId(AtomicUsize::new(largest.map_or(1, following)))
+    }
+
+    fn peek(&self, requested: ID) -> Result<ID, ApiError> {
+        if requested != 0 {
+            return Ok(requested);
+        }
+        match self.0.load(Ordering::SeqCst) {
+            0 => Err(out_of_ids()),
+            next => Ok(next),
+        }
+    }
+
+    /// Resolves the id for a new todo, allocating one if none was requested.
+    /// Explicit ids move the counter past them so they are never reissued.
+    fn assign(&self, requested: ID) -> Result<ID, ApiError> {
+        if requested == 0 {
+            return self
+                .0
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |next| {
+                    if next == 0 {
+                        None
+                    } else {
+                        Some(following(next))
+                    }
+                })
+                .map_err(|_| out_of_ids());
+        }
+        // Once the counter is spent it stays spent; it only ever moves up.
+        let _ = self
+            .0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |next| {
+                if next != 0 && requested >= next {
+                    Some(following(requested))
+                } else {
+                    None
+                }
+            });
+        Ok(requested)
+    }
+}
+
+/// When each deleted todo was removed, so syncing clients can learn about it.
+type Tombstones = RwLock<HashMap<ID, DateTime<Utc>>>;
+
+/// Set while `POST /import.json` builds the imported store beside the live
+/// one. Reads keep seeing the old todos; writes are turned away until the
+/// new store is swapped in, since they would be lost with the old one.
+struct Importing(AtomicBool);
+
+/// Clears `Importing` when the import finishes, fails or panics.
+struct ImportRun<'a>(&'a AtomicBool);
+
+impl Drop for ImportRun<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+impl Importing {
+    /// Marks an import as started, unless one already is.
+    fn start(&self) -> Option<ImportRun> {
+        self.0
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()
+            .map(|_| ImportRun(&self.0))
+    }
+}
+
+/// What kind of change a journaled mutation made.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ChangeOp {
+    Create,
+    Update,
+    Delete,
+}
+
+impl ChangeOp {
+    fn of(entry: &wal::Entry) -> ChangeOp {
+        match *entry {
+            wal::Entry::Put { ref todo } if todo.version == 1 => ChangeOp::Create,
+            wal::Entry::Put { .. } => ChangeOp::Update,
+            wal::Entry::Delete { .. } => ChangeOp::Delete,
+        }
+    }
+
+    fn parse(name: &str) -> Option<ChangeOp> {
+        match name {
+            "create" => Some(ChangeOp::Create),
+            "update" => Some(ChangeOp::Update),
+            "delete" => Some(ChangeOp::Delete),
+            _ => None,
+        }
+    }
+}
+
+impl<'v> FromFormValue<'v> for ChangeOp {
+    type Error = &'v RawStr;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<ChangeOp, &'v RawStr> {
+        ChangeOp::parse(form_value.as_str()).ok_or(form_value)
+    }
+}
+
+/// One entry in the audit log.
+#[derive(Serialize)]
+struct Change {
+    op: ChangeOp,
+    id: ID,
+    at: DateTime<Utc>,
+}
+
+/// The audit log: the latest `MAX_CHANGES` changes, oldest first. Unlike the
+/// write-ahead log it is kept in memory only.
+struct ChangeLog(Mutex<VecDeque<Change>>);
+
+const MAX_CHANGES: usize = 1000;
+
+/// Read-only copies of the store, by name.
+type Snapshots = Mutex<HashMap<String, HashMap<ID, Todo>>>;
+
+/// Server settings read from the Rocket config when the app is built.
+struct Settings {
+    read_only: bool,
+    /// How long a write waits for the store lock before giving up with `503`.
+    lock_timeout: Duration,
+    min_priority: usize,
+    max_priority: usize,
+    /// The priority triage resets todos to, kept within the range.
+    default_priority: Priority,
+    /// The longest title accepted, counted in characters rather than bytes
+    /// so multibyte titles aren't penalized.
+    max_title_length: usize,
+    /// The most distinct tags one todo may carry.
+    max_tags: usize,
+    /// Todo fields left out of every response, for privacy.
+    redacted_fields: Vec<String>,
+    default_sort: SortOrder,
+    /// Enables bearer JWT authentication when set.
+    jwt_secret: Option<String>,
+    /// Where to post an event for every change, if anywhere.
+    webhook_url: Option<String>,
+    /// Rejects request bodies with fields todos don't have.
+    strict_fields: bool,
+    /// Reads bodies without a content type, or typed `application/*+json`,
+    /// as JSON.
+    lenient_content_type: bool,
+    /// Fields no two todos may share a value of.
+    unique_fields: Vec<String>,
+    /// How long after creation a todo of each priority is due, when created
+    /// without a due date, from `due_in_days = { "5" = 1 }`.
+    default_due: HashMap<usize, chrono::Duration>,
+    /// What every title must start with, if anything.
+    title_prefix: Option<String>,
+}
+
+/// How the index is ordered, by `?sort=` or the `default_sort` config.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SortOrder {
+    Id,
+    Priority,
+    Created,
+    Position,
+}
+
+impl SortOrder {
+    fn parse(name: &str) -> Option<SortOrder> {
+        match name {
+            "id" => Some(SortOrder::Id),
+            "priority" => Some(SortOrder::Priority),
+            "created" => Some(SortOrder::Created),
+            "position" => Some(SortOrder::Position),
+            _ => None,
+        }
+    }
+
+    fn sort(self, todos: &mut [&Todo]) {
+        match self {
+            SortOrder::Id => todos.sort_by_key(|todo| todo.id),
+            SortOrder::Priority => todos.sort_by(by_priority),
+            SortOrder::Created => todos.sort_by_key(|todo| (todo.created_at, todo.id)),
+            SortOrder::Position => {
+                todos.sort_by_key(|todo| (todo.position.is_none(), todo.position, todo.id))
+            }
+        }
+    }
+}
+
+impl<'v> FromFormValue<'v> for SortOrder {
+    type Error = &'v RawStr;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<SortOrder, &'v RawStr> {
+        SortOrder::parse(form_value.as_str()).ok_or(form_value)
+    }
+}
+
+/// `max_title_length` unless configured otherwise.
+const DEFAULT_MAX_TITLE_LENGTH: usize = 256;
+
+impl Settings {
+    fn from_config(config: &Config) -> Settings {
+        let lock_timeout_ms = config.get_int("lock_timeout_ms").unwrap_or(1000);
+        let min_priority = config.get_int("min_priority").unwrap_or(1).max(0) as usize;
+        let max_priority = config.get_int("max_priority").unwrap_or(5).max(0) as usize;
+        let default_priority = config.get_int("default_priority").unwrap_or(3).max(0) as usize;
+        Settings {
+            read_only: config.get_bool("read_only").unwrap_or(false),
+            lock_timeout: Duration::from_millis(lock_timeout_ms.max(0) as u64),
+            min_priority,
+            max_priority,
+            default_priority: Priority(default_priority.max(min_priority).min(max_priority)),
+            max_tags: config.get_int("max_tags").unwrap_or(20).max(0) as usize,
+            max_title_length: config
+                .get_int("max_title_length")
+                .map_or(DEFAULT_MAX_TITLE_LENGTH, |length| length.max(0) as usize),
+            redacted_fields: config
+                .get_slice("redacted_fields")
+                .map(|fields| {
+                    fields
+                        .iter()
+                        .filter_map(|field| field.as_str())
+                        .map(|field| field.to_string())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            default_sort: config
+                .get_str("default_sort")
+                .ok()
+                .and_then(SortOrder::parse)
+                .unwrap_or(SortOrder::Id),
+            jwt_secret: config
+                .get_str("jwt_secret")
+                .ok()
+                .map(|secret| secret.to_string()),
+            webhook_url: config
+                .get_str("webhook_url")
+                .ok()
+                .map(|url| url.to_string()),
+            strict_fields: config.get_bool("strict_fields").unwrap_or(false),
+            lenient_content_type: config.get_bool("lenient_content_type").unwrap_or(false),
+            unique_fields: config
+                .get_slice("unique_fields")
+                .map(|fields| {
+                    fields
+                        .iter()
+                        .filter_map(|field| field.as_str())
+                        .map(|field| field.to_string())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            default_due: config
+                .get_table("due_in_days")
+                .map(|offsets| {
+                    offsets
+                        .iter()
+                        .filter_map(|(priority, days)| {
+                            let days = chrono::Duration::try_days(days.as_integer()?)?;
+                            Some((priority.parse().ok()?, days))
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            title_prefix: config
+                .get_str("title_prefix")
+                .ok()
+                .map(|prefix| prefix.to_string()),
+        }
+    }
+
+    fn check_priority(&self, priority: Priority) -> Result<(), ApiError> {
+        match self.priority_problem(priority) {
+            Some(reason) => Err(error(Status::BadRequest, &reason)),
+            None => Ok(()),
+        }
+    }
+
+    /// Fails with `409` if `todo` has the same value as another stored todo
+    /// for one of the `unique_fields`. Missing and null values never clash.
+    fn check_unique(&self, todo: &Todo, todos: &HashMap<ID, Todo>) -> Result<(), ApiError> {
+        if self.unique_fields.is_empty() {
+            return Ok(());
+        }
+        let value = serde_json::to_value(todo)?;
+        for other in todos.values().filter(|other| other.id != todo.id) {
+            let other_value = serde_json::to_value(other)?;
+            for field in &self.unique_fields {
+                match (value.get(field), other_value.get(field)) {
+                    (Some(mine), Some(theirs)) if !mine.is_null() && mine == theirs => {
+                        let reason = format!(
+                            "Field `{}` must be unique, but todo {} has the same value.",
+                            field, other.id
+                        );
+                        return Err(error(Status::Conflict, &reason));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn priority_problem(&self, priority: Priority) -> Option<String> {
+        if (self.min_priority..=self.max_priority).contains(&priority.0) {
+            None
+        } else {
+            Some(format!(
+                "Priority must be between {} and {}.",
+                self.min_priority, self.max_priority
+            ))
+        }
+    }
+}
+
+/// The write-ahead log, when `wal_path` is configured.
+struct Journal(Option<wal::Wal>);
+
+/// The serialized, unfiltered index, kept for `index_cache_ttl_ms` when
+/// configured and dropped on any write.
+struct IndexCache {
+    ttl: Option<Duration>,
+    cached: Mutex<Option<(Instant, String)>>,
+}
+
+impl IndexCache {
+    fn from_config(config: &Config) -> IndexCache {
+        IndexCache {
+            ttl: config
+                .get_int("index_cache_ttl_ms")
+                .ok()
+                .map(|ttl| Duration::from_millis(ttl.max(0) as u64)),
+            cached: Mutex::new(None),
+        }
+    }
+
+    // The cached body is replaced whole, so one left behind by a panic is
+    // still a complete body and the poisoned lock can be used as is.
+    fn get(&self) -> Option<String> {
+        let ttl = self.ttl?;
+        match *self.cached.lock().unwrap_or_else(PoisonError::into_inner) {
+            Some((at, ref body)) if at.elapsed() < ttl => Some(body.clone()),
+            _ => None,
+        }
+    }
+
+    fn put(&self, body: &str) {
+        if self.ttl.is_some() {
+            let mut cached = self.cached.lock().unwrap_or_else(PoisonError::into_inner);
+            *cached = Some((Instant::now(), body.to_string()));
+        }
+    }
+
+    fn invalidate(&self) {
+        *self.cached.lock().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+}
+
+/// Guards routes that modify the store, failing with `503` while the
+/// server is in read-only mode. Mutations go through it to the journal.
+struct Writable<'r> {
+    journal: State<'r, Journal>,
+    cache: State<'r, IndexCache>,
+    importing: State<'r, Importing>,
+    changes: State<'r, ChangeLog>,
+    webhook: State<'r, Webhook>,
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for Writable<'r> {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        let settings = request.guard::<State<Settings>>()?;
+        if settings.read_only {
+            Outcome::Failure((Status::ServiceUnavailable, ()))
+        } else {
+            let journal = request.guard::<State<Journal>>()?;
+            let cache = request.guard::<State<IndexCache>>()?;
+            let importing = request.guard::<State<Importing>>()?;
+            let changes = request.guard::<State<ChangeLog>>()?;
+            let webhook = request.guard::<State<Webhook>>()?;
+            Outcome::Success(Writable {
+                journal,
+                cache,
+                importing,
+                changes,
+                webhook,
+            })
+        }
+    }
+}
+
+fn log_failed(e: io::Error) -> ApiError {
+    let reason = format!("Could not write the log: {}.", e);
+    error(Status::InternalServerError, &reason)
+}
+
+impl Writable<'_> {
+    /// Journals a mutation before the caller applies it to the store. Callers
+    /// hold the write lock, so the index can't be re-cached until it's done.
+    /// Fails with `503` while an import is being built.
+    fn log(&self, entry: &wal::Entry) -> Result<(), ApiError> {
+        if self.importing.0.load(Ordering::SeqCst) {
+            return Err(retry_later("An import is in progress."));
+        }
+        self.cache.invalidate();
+        if let Some(ref wal) = self.journal.0 {
+            wal.append(entry).map_err(log_failed)?;
+        }
+        let mut changes = self.changes.0.lock()?;
+        if changes.len() == MAX_CHANGES {
+            changes.pop_front();
+        }
+        changes.push_back(Change {
+            op: ChangeOp::of(entry),
+            id: match *entry {
+                wal::Entry::Put { ref todo } => todo.id,
+                wal::Entry::Delete { id } => id,
+            },
+            at: Utc::now(),
+        });
+        self.webhook.send(entry);
+        Ok(())
+    }
+}
+
+/// Webhook events that may wait for delivery before new ones are dropped.
+const WEBHOOK_QUEUE: usize = 256;
+
+/// Queues events for the `webhook_url`, which one worker thread posts with a
+/// single reused client. Holds nothing when no webhook is configured.
+struct Webhook(Option<SyncSender<JsonValue>>);
+
+impl Webhook {
+    fn start(url: Option<String>) -> Webhook {
+        let url = match url {
+            Some(url) => url,
+            None => return Webhook(None),
+        };
+        let (events, queue) = mpsc::sync_channel::<JsonValue>(WEBHOOK_QUEUE);
+        // The worker stops once the sender is dropped with the server.
+        thread::spawn(move || {
+            let client = reqwest::blocking::Client::new();
+            for event in queue {
+                let sent = client
+                    .post(&url)
+                    .json(&*event)
+                    .send()
+                    .and_then(|res| res.error_for_status());
+                if let Err(e) = sent {
+                    log::warn!("Webhook to {} failed: {}", url, e);
+                }
+            }
+        });
+        Webhook(Some(events))
+    }
+
+    /// Queues `entry` as a `created`, `updated` or `deleted` event. Failures
+    /// are only logged: a webhook never fails or holds up the request that
+    /// triggered it.
+    fn send(&self, entry: &wal::Entry) {
+        let events = match self.0 {
+            Some(ref events) => events,
+            None => return,
+        };
+        let event = match *entry {
+            wal::Entry::Put { ref todo } if todo.version == 1 => {
+                json!({ "event": "created", "todo": todo })
+            }
+            wal::Entry::Put { ref todo } => json!({ "event": "updated", "todo": todo }),
+            wal::Entry::Delete { id } => json!({ "event": "deleted", "id": id }),
+        };
+        if let Err(e) = events.try_send(event) {
+            log::warn!("Dropping webhook event: {}", e);
+        }
+    }
+}
+
+/// Loads the store, replaying the write-ahead log if one is configured.
+/// Seeds are checked by `validator` like any other new todo.
+fn open_store(config: &Config, validator: &Validator) -> io::Result<(HashMap<ID, Todo>, Journal)> {
+    let (mut todos, journal) = match config.get_str("wal_path") {
+        Ok(path) => {
+            let todos = wal::replay(Path::new(path))?;
+            (todos, Journal(Some(wal::Wal::open(path)?)))
+        }
+        Err(_) => (HashMap::new(), Journal(None)),
+    };
+    // Only an empty store is seeded, so persisted todos are never replaced.
+    if todos.is_empty() {
+        if let Ok(path) = config.get_str("seed_path") {
+            let settings = Settings::from_config(config);
+            todos = seed_store(Path::new(path), &journal, &settings, validator)?;
+        }
+    }
+    Ok((todos, journal))
+}
+
+/// Reads todos from the JSON array at `path` for a store that starts out
+/// empty. Todos without an id are numbered after the rest. Seeds are
+/// journaled, so they outlive the first restart like any other todo. A
+/// seed file that can't be read or parsed is skipped with a warning, as is
+/// each seed that fails the checks `POST /` makes.
+fn seed_store(
+    path: &Path,
+    journal: &Journal,
+    settings: &Settings,
+    validator: &Validator,
+) -> io::Result<HashMap<ID, Todo>> {
+    let seeds: Vec<Todo> = match std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|json| serde_json::from_str(&json).map_err(|e| e.to_string()))
+    {
+        Ok(seeds) => seeds,
+        Err(e) => {
+            log::warn!("Ignoring seed file {}: {}", path.display(), e);
+            return Ok(HashMap::new());
+        }
+    };
+
+    let mut valid = Vec::new();
+    for mut todo in seeds {
+        match todo_problems(&mut todo, settings, validator)
+            .into_iter()
+            .next()
+        {
+            Some((_, reason)) => log::warn!("Skipping seed {:?}: {}", todo.title, reason),
+            None => valid.push(todo),
+        }
+    }
+
+    let now = Utc::now();
+    let mut next_id = valid.iter().map(|todo| todo.id).max().map_or(1, following);
+    let mut todos = HashMap::new();
+    for mut todo in valid {
+        if todo.id == 0 {
+            if next_id == 0 {
+                log::warn!("Skipping seed {:?}: no ids are left.", todo.title);
+                continue;
+            }
+            todo.id = next_id;
+            next_id = following(next_id);
+        }
+        todo.created_at = now;
+        todo.updated_at = now;
+        todo.version = 1;
+        track_completion(&mut todo, None);
+        if let Some(ref wal) = journal.0 {
+            wal.append(&wal::Entry::put(&todo))?;
+        }
+        todos.insert(todo.id, todo);
+    }
+    Ok(todos)
+}
+
+/// Why a fairing turned a request away before routing, if one did.
+struct Rejected(Option<(Status, String)>);
+
+/// Keeps a request from reaching any handler, since no route answers
+/// OPTIONS, and has `Rejections` answer it with `status` instead. The first
+/// rejection of a request wins.
+fn reject(request: &mut Request, status: Status, reason: &str) {
+    request.local_cache(|| Rejected(Some((status, reason.to_string()))));
+    request.set_method(Method::Options);
+}
+
+/// Answers requests turned away by `reject` with a JSON error.
+struct Rejections;
+
+impl Fairing for Rejections {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rejections",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        if let Some((status, ref reason)) = request.local_cache(|| Rejected(None)).0 {
+            let body = json!({ "status": "error", "reason": reason });
+            response.set_status(status);
+            response.set_header(ContentType::JSON);
+            response.set_sized_body(Cursor::new(body.to_string()));
+        }
+    }
+}
+
+/// Rejects requests whose `Origin` isn't allowlisted with `403`. Requests
+/// without an `Origin`, such as server-to-server calls, pass.
+struct SameOrigin {
+    allowed: Vec<String>,
+}
+
+impl SameOrigin {
+    /// Enforcement is on only when `allowed_origins` is configured.
+    fn from_config(config: &Config) -> Option<SameOrigin> {
+        let origins = config.get_slice("allowed_origins").ok()?;
+        let allowed = origins
+            .iter()
+            .filter_map(|origin| origin.as_str())
+            .map(|origin| origin.to_string())
+            .collect();
+        Some(SameOrigin { allowed })
+    }
+}
+
+impl Fairing for SameOrigin {
+    fn info(&self) -> Info {
+        Info {
+            name: "Same-origin enforcement",
+            kind: Kind::Request,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let allowed = match request.headers().get_one("Origin") {
+            Some(origin) => self.allowed.iter().any(|allowed| allowed == origin),
+            None => true,
+        };
+        if !allowed {
+            reject(request, Status::Forbidden, "Origin is not allowed.");
+        }
+    }
+}
+
+/// With `lenient_content_type = true`, request bodies sent without a
+/// `Content-Type`, or as `application/*+json`, are read as JSON by `Body`,
+/// and bodies of any other type get `415` instead of a confusing `404`.
+/// Rocket caches the content type before request fairings run, so it can't
+/// be rewritten here; routes that take a body leave `format` off and let
+/// `Body` negotiate instead.
+struct LenientContentType;
+
+/// Whether `content_type` is `application/*+json`.
+fn is_json_suffix(content_type: &ContentType) -> bool {
+    content_type.top() == "application" && content_type.sub().as_str().ends_with("+json")
+}
+
+impl Fairing for LenientContentType {
+    fn info(&self) -> Info {
+        Info {
+            name: "Lenient content type",
+            kind: Kind::Request,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        if !request.method().supports_payload() {
+            return;
+        }
+        let supported = request.content_type().map_or(true, |content_type| {
+            content_type.is_json() || content_type.is_msgpack() || is_json_suffix(content_type)
+        });
+        if !supported {
+            reject(
+                request,
+                Status::UnsupportedMediaType,
+                "Request bodies must be JSON.",
+            );
+        }
+    }
+}
+
+/// Treats a POST that repeats, byte for byte, one the same client sent to
+/// the same URI within the last `dedup_window_ms` as a double submission,
+/// and answers it with the first response instead of handling it again.
+/// Fairings can only peek at the start of a body, so longer bodies are
+/// never deduplicated.
+struct DedupWindow {
+    window: Duration,
+    seen: Mutex<HashMap<u64, Submission>>,
+}
+
+/// How much of a body Rocket lets fairings peek at.
+const MAX_DEDUP_BODY: usize = 512;
+
+/// A response as first sent, to replay to duplicates.
+type SentResponse = (Status, Option<ContentType>, String);
+
+/// A submission seen within the window, and its response once sent.
+struct Submission {
+    at: Instant,
+    response: Option<SentResponse>,
+}
+
+/// What `DedupWindow` decided about a request.
+enum Dedup {
+    Untracked,
+    First(u64),
+    Replay(Box<SentResponse>),
+}
+
+impl DedupWindow {
+    /// Identifies a submission by client address, URI and body.
+    fn key(request: &Request, body: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        request.client_ip().hash(&mut hasher);
+        request.uri().to_string().hash(&mut hasher);
+        body.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl Fairing for DedupWindow {
+    fn info(&self) -> Info {
+        Info {
+            name: "Dedup window",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, data: &Data) {
+        let body = data.peek();
+        if request.method() != Method::Post || !data.peek_complete() || body.len() >= MAX_DEDUP_BODY
+        {
+            return;
+        }
+        let key = DedupWindow::key(request, body);
+        let mut seen = self.seen.lock().unwrap_or_else(PoisonError::into_inner);
+        seen.retain(|_, submission| submission.at.elapsed() < self.window);
+        match seen.get(&key).map(|submission| submission.response.clone()) {
+            Some(Some(response)) => {
+                request.local_cache(|| Dedup::Replay(Box::new(response)));
+                request.set_method(Method::Options);
+            }
+            Some(None) => reject(
+                request,
+                Status::Conflict,
+                "An identical request is still being handled.",
+            ),
+            None => {
+                seen.insert(
+                    key,
+                    Submission {
+                        at: Instant::now(),
+                        response: None,
+                    },
+                );
+                request.local_cache(|| Dedup::First(key));
+            }
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        match *request.local_cache(|| Dedup::Untracked) {
+            Dedup::Untracked => {}
+            Dedup::First(key) => {
+                let mut seen = self.seen.lock().unwrap_or_else(PoisonError::into_inner);
+                // Failures aren't remembered, so a corrected retry goes through.
+                if response.status().class() != StatusClass::Success {
+                    seen.remove(&key);
+                    return;
+                }
+                let body = response.body_string().unwrap_or_default();
+                if let Some(submission) = seen.get_mut(&key) {
+                    let sent = (response.status(), response.content_type(), body.clone());
+                    submission.response = Some(sent);
+                }
+                response.set_sized_body(Cursor::new(body));
+            }
+            Dedup::Replay(ref sent) => {
+                let (status, ref content_type, ref body) = **sent;
+                response.set_status(status);
+                if let Some(content_type) = content_type {
+                    response.set_header(content_type.clone());
+                }
+                response.set_sized_body(Cursor::new(body.clone()));
+            }
+        }
+    }
+}
+
+/// Archives todos completed over `auto_archive_days` ago, checking at most
+/// every `auto_archive_interval_secs` (an hour by default). Managed state
+/// can't outlive the borrow of the server, so the pass runs ahead of the
+/// first request after startup and after each interval since, rather than
+/// on a thread of its own.
+struct AutoArchive {
+    after: chrono::Duration,
+    interval: Duration,
+    last_run: Mutex<Option<Instant>>,
+}
+
+const DEFAULT_AUTO_ARCHIVE_INTERVAL_SECS: u64 = 3600;
+
+impl AutoArchive {
+    /// Archiving is on only when `auto_archive_days` is configured, and to
+    /// a number of days that reaches back to a representable date.
+    fn from_config(config: &Config) -> Option<AutoArchive> {
+        let days = config.get_int("auto_archive_days").ok()?;
+        let after = chrono::Duration::try_days(days.max(0))
+            .filter(|after| Utc::now().checked_sub_signed(*after).is_some());
+        let after = match after {
+            Some(after) => after,
+            None => {
+                log::warn!("Ignoring auto_archive_days = {}: out of range.", days);
+                return None;
+            }
+        };
+        let interval = match config.get_int("auto_archive_interval_secs") {
+            Ok(secs) if secs > 0 => secs as u64,
+            _ => DEFAULT_AUTO_ARCHIVE_INTERVAL_SECS,
+        };
+        Some(AutoArchive {
+            after,
+            interval: Duration::from_secs(interval),
+            last_run: Mutex::new(None),
+        })
+    }
+
+    fn run(&self, request: &Request) -> Result<usize, ApiError> {
+        let (writable, todos, settings) = match (
+            request.guard::<Writable>(),
+            request.guard::<State<TodoRepository>>(),
+            request.guard::<State<Settings>>(),
+        ) {
+            (Outcome::Success(writable), Outcome::Success(todos), Outcome::Success(settings)) => {
+                (writable, todos, settings)
+            }
+            _ => return Ok(0),
+        };
+        // Worked out before the lock is taken, so a panic can't poison it.
+        let cutoff = match Utc::now().checked_sub_signed(self.after) {
+            Some(cutoff) => cutoff,
+            None => return Ok(0),
+        };
+        let mut hashmap = write_store(&todos, &settings)?;
+        archive_completed(&mut hashmap, cutoff, |todo| {
+            writable.log(&wal::Entry::put(todo))
+        })
+    }
+}
+
+impl Fairing for AutoArchive {
+    fn info(&self) -> Info {
+        Info {
+            name: "Auto-archive",
+            kind: Kind::Request,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        {
+            let mut last_run = self.last_run.lock().unwrap_or_else(PoisonError::into_inner);
+            if last_run.map_or(false, |at| at.elapsed() < self.interval) {
+                return;
+            }
+            *last_run = Some(Instant::now());
+        }
+        if self.run(request).is_err() {
+            log::warn!("Auto-archive pass failed; retrying next interval.");
+        }
+    }
+}
+
+/// Limits each client address to `rate_limit_per_sec` requests a second,
+/// in bursts of up to `rate_limit_burst`, with a leaky bucket per address.
+/// Requests over the limit get `429`, or with `rate_limit_mode = "delay"`
+/// are held back until their bucket has room, for at most
+/// `rate_limit_max_delay_ms` (250 by default).
+struct RateLimit {
+    rate: f64,
+    burst: f64,
+    /// The longest a request is held back, in delay mode.
+    max_delay: Option<Duration>,
+    buckets: Mutex<HashMap<Option<IpAddr>, Bucket>>,
+}
+
+/// How many requests a client's bucket held as of `updated`.
+struct Bucket {
+    level: f64,
+    updated: Instant,
+}
+
+/// How long to hold back a request arriving at a bucket holding `level`
+/// requests, so that it goes through once the bucket has leaked enough to
+/// fit it: nothing while there's room, and never longer than `max`.
+fn backpressure(level: f64, burst: f64, rate: f64, max: Duration) -> Duration {
+    let overflow = level + 1.0 - burst;
+    if overflow <= 0.0 {
+        return Duration::from_secs(0);
+    }
+    cmp::min(Duration::from_secs_f64(overflow / rate), max)
+}
+
+impl RateLimit {
+    /// Limiting is on only when `rate_limit_per_sec` is configured.
+    fn from_config(config: &Config) -> Option<RateLimit> {
+        let rate = config
+            .get_int("rate_limit_per_sec")
+            .ok()
+            .filter(|&rate| rate > 0)? as f64;
+        let burst = match config.get_int("rate_limit_burst") {
+            Ok(burst) if burst > 0 => burst as f64,
+            _ => rate,
+        };
+        let max_delay = match config.get_str("rate_limit_mode") {
+            Ok("delay") => {
+                let ms = config.get_int("rate_limit_max_delay_ms").unwrap_or(250);
+                Some(Duration::from_millis(ms.max(0) as u64))
+            }
+            _ => None,
+        };
+        Some(RateLimit {
+            rate,
+            burst,
+            max_delay,
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+}
+
+impl Fairing for RateLimit {
+    fn info(&self) -> Info {
+        Info {
+            name: "Rate limit",
+            kind: Kind::Request,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let now = Instant::now();
+        let wait = {
+            let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+            let bucket = buckets.entry(request.client_ip()).or_insert(Bucket {
+                level: 0.0,
+                updated: now,
+            });
+            let leaked = now.duration_since(bucket.updated).as_secs_f64() * self.rate;
+            bucket.level = (bucket.level - leaked).max(0.0);
+            bucket.updated = now;
+            match self.max_delay {
+                Some(max) => {
+                    let wait = backpressure(bucket.level, self.burst, self.rate, max);
+                    // Requests held back for `max` still go through early,
+                    // so the bucket can't fill past what `max` drains.
+                    let full = self.burst + self.rate * max.as_secs_f64();
+                    bucket.level = (bucket.level + 1.0).min(full);
+                    Some(wait)
+                }
+                None if bucket.level + 1.0 > self.burst => None,
+                None => {
+                    bucket.level += 1.0;
+                    Some(Duration::from_secs(0))
+                }
+            }
+        };
+        match wait {
+            Some(wait) => thread::sleep(wait),
+            None => reject(request, Status::TooManyRequests, "Too many requests."),
+        }
+    }
+}
+
+/// How to answer a request's `Expect` header: `100 Continue` when the body
+/// would be accepted, `413` when it's declared over `limit`, and `417` for
+/// unknown expectations or methods that don't take a body.
+fn continue_status(
+    method: Method,
+    expect: &str,
+    content_length: Option<u64>,
+    limit: u64,
+) -> Status {
+    let takes_body = matches!(method, Method::Post | Method::Put | Method::Patch);
+    if !expect.trim().eq_ignore_ascii_case("100-continue") || !takes_body {
+        Status::ExpectationFailed
+    } else if content_length.map_or(false, |length| length > limit) {
+        Status::PayloadTooLarge
+    } else {
+        Status::Continue
+    }
+}
+
+/// Rejects requests whose `Expect` header `continue_status` refuses. The
+/// underlying server already sends `100 Continue` on its own, so this can't
+/// stop the upload, but it keeps the refused request away from handlers.
+struct ExpectContinue;
+
+impl Fairing for ExpectContinue {
+    fn info(&self) -> Info {
+        Info {
+            name: "Expect: 100-continue",
+            kind: Kind::Request,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        let expect = match request.headers().get_one("Expect") {
+            Some(expect) => expect.to_string(),
+            None => return,
+        };
+        let content_length = request
+            .headers()
+            .get_one("Content-Length")
+            .and_then(|length| length.parse().ok());
+        let limit_name = match request.content_type() {
+            Some(content_type) if content_type.is_msgpack() => "msgpack",
+            _ => "json",
+        };
+        let limit = request.limits().get(limit_name).unwrap_or(1 << 20);
+
+        let status = continue_status(request.method(), &expect, content_length, limit);
+        if status != Status::Continue {
+            reject(request, status, status.reason);
+        }
+    }
+}
+
+/// The claims this server reads from a bearer token.
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    /// Space-separated, e.g. `"todo:read todo:write"`.
+    scope: String,
+    exp: u64,
+}
+
+/// A request carrying a valid bearer JWT, signed with `jwt_secret`, whose
+/// scopes cover the method: `todo:read` for GET and `todo:write` otherwise.
+/// Fails with `401` for a missing, invalid or expired token and `403` for a
+/// missing scope.
+struct Authorized;
+
+impl<'a, 'r> FromRequest<'a, 'r> for Authorized {
+    type Error = &'static str;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, &'static str> {
+        let secret = match request.guard::<State<Settings>>() {
+            Outcome::Success(settings) => settings.jwt_secret.clone(),
+            _ => None,
+        };
+        let secret = match secret {
+            Some(secret) => secret,
+            None => return Outcome::Success(Authorized),
+        };
+
+        let token = match request.headers().get_one("Authorization") {
+            Some(value) if value.starts_with("Bearer ") => &value["Bearer ".len()..],
+            _ => return Outcome::Failure((Status::Unauthorized, "A bearer token is required.")),
+        };
+        let key = DecodingKey::from_secret(secret.as_bytes());
+        let claims = match jsonwebtoken::decode::<Claims>(token, &key, &Validation::default()) {
+            Ok(data) => data.claims,
+            Err(_) => {
+                return Outcome::Failure((Status::Unauthorized, "Token is invalid or expired."))
+            }
+        };
+
+        let needed = match request.method() {
+            Method::Get | Method::Head => "todo:read",
+            _ => "todo:write",
+        };
+        if claims.scope.split_whitespace().any(|scope| scope == needed) {
+            Outcome::Success(Authorized)
+        } else {
+            Outcome::Failure((Status::Forbidden, "Token lacks the required scope."))
+        }
+    }
+}
+
+/// Requires `Authorized` on every request when `jwt_secret` is configured.
+struct JwtAuth;
+
+impl Fairing for JwtAuth {
+    fn info(&self) -> Info {
+        Info {
+            name: "JWT authentication",
+            kind: Kind::Request,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        if let Outcome::Failure((status, reason)) = request.guard::<Authorized>() {
+            reject(request, status, reason);
+        }
+    }
+}
+
+/// One request as logged with `log_format = "json"`.
+#[derive(Serialize)]
+struct LogEntry<'a> {
+    ts: DateTime<Utc>,
+    method: &'a str,
+    path: &'a str,
+    status: u16,
+    ms: u64,
+}
 
-type ID = usize;
+impl LogEntry<'_> {
+    fn to_line(&self) -> serde_json::Result<String> {
+        serde_json::to_string(self)
+    }
+}
+
+/// When the request was received, for `JsonLog` and `SlowRequestLog`.
+struct Received(Instant);
+
+/// Writes one JSON object per request to stdout for log processors. Rocket's
+/// own human-readable logging is unaffected.
+struct JsonLog;
+
+impl Fairing for JsonLog {
+    fn info(&self) -> Info {
+        Info {
+            name: "JSON request log",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        request.local_cache(|| Received(Instant::now()));
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let received = request.local_cache(|| Received(Instant::now()));
+        let entry = LogEntry {
+            ts: Utc::now(),
+            method: request.method().as_str(),
+            path: request.uri().path(),
+            status: response.status().code,
+            ms: received.0.elapsed().as_millis() as u64,
+        };
+        if let Ok(line) = entry.to_line() {
+            println!("{}", line);
+        }
+    }
+}
+
+/// Logs a warning for requests slower than `slow_request_ms` (500ms by
+/// default), which usually means they waited on the store lock.
+struct SlowRequestLog {
+    threshold: Duration,
+}
+
+const DEFAULT_SLOW_REQUEST_MS: u64 = 500;
+
+fn is_slow(elapsed: Duration, threshold: Duration) -> bool {
+    elapsed > threshold
+}
+
+impl Fairing for SlowRequestLog {
+    fn info(&self) -> Info {
+        Info {
+            name: "Slow request log",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    fn on_request(&self, request: &mut Request, _: &Data) {
+        request.local_cache(|| Received(Instant::now()));
+    }
+
+    fn on_response(&self, request: &Request, _: &mut Response) {
+        let elapsed = request.local_cache(|| Received(Instant::now())).0.elapsed();
+        if is_slow(elapsed, self.threshold) {
+            log::warn!(
+                "Slow request: {} {} took {}ms",
+                request.method(),
+                request.uri().path(),
+                elapsed.as_millis()
+            );
+        }
+    }
+}
+
+/// Wraps successful JSON responses as `{"data": ..., "meta": {"count": n}}`
+/// when `envelope = true`. Errors keep their usual shape, and ranged bodies
+/// are left as they are so a range slices the bytes a full download gets.
+struct Envelope;
+
+impl Fairing for Envelope {
+    fn info(&self) -> Info {
+        Info {
+            name: "Response envelope",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, _: &Request, response: &mut Response) {
+        if response.status().class() != StatusClass::Success
+            || response.content_type() != Some(ContentType::JSON)
+            || response.headers().contains("Accept-Ranges")
+        {
+            return;
+        }
+        let body = match response.body_string() {
+            Some(body) => body,
+            None => return,
+        };
+        let payload: serde_json::Value = match serde_json::from_str(&body) {
+            Ok(payload) => payload,
+            Err(_) => {
+                // Reading the body used it up; give the client it back as is.
+                response.set_sized_body(Cursor::new(body));
+                return;
+            }
+        };
+
+        let count = payload.as_array().map_or(1, |items| items.len());
+        let body = json!({ "data": payload, "meta": { "count": count } });
+        response.set_sized_body(Cursor::new(body.to_string()));
+    }
+}
+
+/// Renames JSON response keys to camelCase (`created_at` becomes
+/// `createdAt`) when `field_case = "camel"`. Keys inside a todo's
+/// `metadata` are the client's own and keep their spelling.
+struct CamelCase;
+
+fn camel_case(key: &str) -> String {
+    let mut camel = String::with_capacity(key.len());
+    let mut upper = false;
+    for c in key.chars() {
+        if c == '_' && !camel.is_empty() {
+            upper = true;
+        } else if upper {
+            camel.extend(c.to_uppercase());
+            upper = false;
+        } else {
+            camel.push(c);
+        }
+    }
+    camel
+}
+
+fn camel_case_keys(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(fields) => fields
+            .into_iter()
+            .map(|(key, value)| {
+                let value = if key == "metadata" {
+                    value
+                } else {
+                    camel_case_keys(value)
+                };
+                (camel_case(&key), value)
+            })
+            .collect(),
+        serde_json::Value::Array(items) => items.into_iter().map(camel_case_keys).collect(),
+        other => other,
+    }
+}
+
+impl Fairing for CamelCase {
+    fn info(&self) -> Info {
+        Info {
+            name: "camelCase fields",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, _: &Request, response: &mut Response) {
+        if response.content_type() != Some(ContentType::JSON)
+            || response.headers().contains("Accept-Ranges")
+        {
+            return;
+        }
+        let body = match response.body_string() {
+            Some(body) => body,
+            None => return,
+        };
+        match serde_json::from_str(&body) {
+            Ok(payload) => {
+                let renamed = camel_case_keys(payload).to_string();
+                response.set_sized_body(Cursor::new(renamed));
+            }
+            Err(_) => response.set_sized_body(Cursor::new(body)),
+        }
+    }
+}
+
+/// Indents JSON responses for people reading them, when asked with
+/// `?pretty=true` or by default with `pretty_json = true`. `?pretty=false`
+/// keeps a response compact either way.
+struct PrettyJson {
+    by_default: bool,
+}
+
+fn wants_pretty(request: &Request, by_default: bool) -> bool {
+    let pretty = request
+        .uri()
+        .query()
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .filter_map(|pair| pair.strip_prefix("pretty="))
+        .last();
+    match pretty {
+        Some(value) => value == "true",
+        None => by_default,
+    }
+}
+
+impl Fairing for PrettyJson {
+    fn info(&self) -> Info {
+        Info {
+            name: "Pretty JSON",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        // Ranged bodies are left alone, or their byte offsets would shift.
+        if response.content_type() != Some(ContentType::JSON)
+            || response.headers().contains("Accept-Ranges")
+            || !wants_pretty(request, self.by_default)
+        {
+            return;
+        }
+        let body = match response.body_string() {
+            Some(body) => body,
+            None => return,
+        };
+        let pretty = serde_json::from_str::<serde_json::Value>(&body)
+            .and_then(|payload| serde_json::to_string_pretty(&payload));
+        match pretty {
+            Ok(pretty) => response.set_sized_body(Cursor::new(pretty)),
+            Err(_) => response.set_sized_body(Cursor::new(body)),
+        }
+    }
+}
+
+/// Compresses response bodies of at least `MIN_COMPRESSED_BYTES` with the
+/// best encoding the client accepts, at `compression_level` (6 unless
+/// configured; 0 to 9, or up to 11 for brotli).
+struct ResponseCompression {
+    level: u32,
+}
+
+const MIN_COMPRESSED_BYTES: usize = 1024;
+
+/// Supported response encodings, preferred first when qualities tie.
+const ENCODINGS: &[&str] = &["br", "gzip", "deflate"];
+
+/// The quality an `Accept-Encoding` value gives `encoding`, directly or
+/// through `*`, or `0` when it isn't accepted.
+fn encoding_quality(accept: &str, encoding: &str) -> f32 {
+    let mut wildcard = 0.0;
+    for item in accept.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let quality = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse().ok())
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case(encoding) {
+            return quality;
+        } else if name == "*" {
+            wildcard = quality;
+        }
+    }
+    wildcard
+}
+
+/// The supported encoding `accept` rates highest, if it accepts any.
+fn negotiate_encoding(accept: &str) -> Option<&'static str> {
+    let mut best: Option<(&'static str, f32)> = None;
+    for &encoding in ENCODINGS {
+        let quality = encoding_quality(accept, encoding);
+        if quality > 0.0 && best.map_or(true, |(_, best)| quality > best) {
+            best = Some((encoding, quality));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+fn compress(encoding: &str, level: u32, bytes: &[u8]) -> io::Result<Vec<u8>> {
+    match encoding {
+        "br" => {
+            let mut compressed = Vec::new();
+            {
+                let mut writer =
+                    brotli::CompressorWriter::new(&mut compressed, 4096, cmp::min(level, 11), 22);
+                writer.write_all(bytes)?;
+            }
+            Ok(compressed)
+        }
+        "gzip" => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::new(cmp::min(level, 9)));
+            encoder.write_all(bytes)?;
+            encoder.finish()
+        }
+        // HTTP's `deflate` is the zlib format, not raw deflate.
+        _ => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(cmp::min(level, 9)));
+            encoder.write_all(bytes)?;
+            encoder.finish()
+        }
+    }
+}
+
+impl Fairing for ResponseCompression {
+    fn info(&self) -> Info {
+        Info {
+            name: "Response compression",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        // Ranged bodies are left alone, or their byte offsets would shift.
+        if response.headers().contains("Content-Encoding")
+            || response.headers().contains("Accept-Ranges")
+        {
+            return;
+        }
+        add_vary(response, "Accept-Encoding");
+        let encoding = match request
+            .headers()
+            .get_one("Accept-Encoding")
+            .and_then(negotiate_encoding)
+        {
+            Some(encoding) => encoding,
+            None => return,
+        };
+        let body = match response.body_bytes() {
+            Some(body) => body,
+            None => return,
+        };
+        if body.len() >= MIN_COMPRESSED_BYTES {
+            if let Ok(compressed) = compress(encoding, self.level, &body) {
+                response.set_raw_header("Content-Encoding", encoding);
+                response.set_sized_body(Cursor::new(compressed));
+                return;
+            }
+        }
+        response.set_sized_body(Cursor::new(body));
+    }
+}
+
+/// Adds `header` to the request headers a response's `Vary` says it
+/// depends on, so caches keep its representations apart.
+fn add_vary(response: &mut Response, header: &str) {
+    let vary = match response.headers().get_one("Vary") {
+        Some(vary) => format!("{}, {}", vary, header),
+        None => header.to_string(),
+    };
+    response.set_raw_header("Vary", vary);
+}
+
+/// Hardening headers for production, each off unless configured: `hsts`
+/// (with `hsts_max_age`, a year by default), `nosniff` and `frame_deny`.
+struct SecurityHeaders {
+    headers: Vec<(&'static str, String)>,
+}
+
+const DEFAULT_HSTS_MAX_AGE: i64 = 31_536_000;
+
+impl SecurityHeaders {
+    fn from_config(config: &Config) -> Option<SecurityHeaders> {
+        let enabled = |key| config.get_bool(key).unwrap_or(false);
+        let mut headers = Vec::new();
+        if enabled("hsts") {
+            let max_age = config
+                .get_int("hsts_max_age")
+                .unwrap_or(DEFAULT_HSTS_MAX_AGE);
+            let value = format!("max-age={}; includeSubDomains", max_age.max(0));
+            headers.push(("Strict-Transport-Security", value));
+        }
+        if enabled("nosniff") {
+            headers.push(("X-Content-Type-Options", "nosniff".to_string()));
+        }
+        if enabled("frame_deny") {
+            headers.push(("X-Frame-Options", "DENY".to_string()));
+        }
+        if headers.is_empty() {
+            None
+        } else {
+            Some(SecurityHeaders { headers })
+        }
+    }
+}
+
+impl Fairing for SecurityHeaders {
+    fn info(&self) -> Info {
+        Info {
+            name: "Security headers",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, _: &Request, response: &mut Response) {
+        for (name, value) in &self.headers {
+            response.set_raw_header(*name, value.clone());
+        }
+    }
+}
+
+/// Honors RFC 7240 `Prefer: return=minimal` on writes by answering `204` with
+/// no body. `return=representation`, like no preference, keeps the body.
+struct PreferReturn;
+
+fn prefers_minimal(request: &Request) -> bool {
+    request
+        .headers()
+        .get("Prefer")
+        .flat_map(|value| value.split(','))
+        .any(|preference| preference.trim().eq_ignore_ascii_case("return=minimal"))
+}
+
+impl Fairing for PreferReturn {
+    fn info(&self) -> Info {
+        Info {
+            name: "Prefer: return",
+            kind: Kind::Response,
+        }
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let is_write = matches!(
+            request.method(),
+            Method::Post | Method::Put | Method::Patch | Method::Delete
+        );
+        if is_write && response.status().class() == StatusClass::Success && prefers_minimal(request)
+        {
+            response.set_status(Status::NoContent);
+            response.take_body();
+            response.remove_header("Content-Type");
+            response.set_raw_header("Preference-Applied", "return=minimal");
+        }
+    }
+}
+
+#[derive(Debug, Responder)]
+enum ApiError {
+    Failed(status::Custom<JsonValue>),
+    RetryLater(status::Custom<JsonValue>, Header<'static>),
+}
+
+/// Failures inside the server rather than in the request. Each becomes a
+/// `500` response instead of a panic, so the server keeps serving.
+#[derive(Debug, Error)]
+enum TodoError {
+    /// A request panicked while holding a lock, so what it guards may be
+    /// half-changed. Requests needing it fail until the server restarts.
+    #[error("The store is unavailable after an earlier request failed.")]
+    Poisoned,
+    #[error("Could not serialize a todo: {0}.")]
+    Serialize(#[from] serde_json::Error),
+}
+
+impl<T> From<PoisonError<T>> for TodoError {
+    fn from(_: PoisonError<T>) -> TodoError {
+        TodoError::Poisoned
+    }
+}
+
+impl From<TodoError> for ApiError {
+    fn from(e: TodoError) -> ApiError {
+        error(Status::InternalServerError, &e.to_string())
+    }
+}
+
+impl<T> From<PoisonError<T>> for ApiError {
+    fn from(e: PoisonError<T>) -> ApiError {
+        TodoError::from(e).into()
+    }
+}
+
+impl From<serde_json::Error> for ApiError {
+    fn from(e: serde_json::Error) -> ApiError {
+        TodoError::from(e).into()
+    }
+}
+
+fn error(status: Status, reason: &str) -> ApiError {
+    ApiError::Failed(status::Custom(
+        status,
+        json!({ "status": "error", "reason": reason }),
+    ))
+}
+
+/// A `404` naming the missing todo, unlike the catcher's generic message
+/// for unknown paths.
+fn todo_not_found(id: ID) -> ApiError {
+    error(Status::NotFound, &format!("Todo {} not found.", id))
+}
+
+/// A `503` asking the client to retry in a second.
+fn retry_later(reason: &str) -> ApiError {
+    ApiError::RetryLater(
+        status::Custom(
+            Status::ServiceUnavailable,
+            json!({ "status": "error", "reason": reason }),
+        ),
+        Header::new("Retry-After", "1"),
+    )
+}
+
+/// Takes the store's write lock, retrying until the configured timeout
+/// instead of blocking indefinitely behind a slow holder.
+fn write_store<'a>(
+    todos: &'a TodoRepository,
+    settings: &Settings,
+) -> Result<RwLockWriteGuard<'a, HashMap<ID, Todo>>, ApiError> {
+    let deadline = Instant::now() + settings.lock_timeout;
+    loop {
+        match todos.try_write() {
+            Ok(hashmap) => return Ok(hashmap),
+            Err(TryLockError::Poisoned(e)) => return Err(TodoError::from(e).into()),
+            Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                thread::sleep(Duration::from_millis(1));
+            }
+            Err(TryLockError::WouldBlock) => return Err(retry_later("Store is busy.")),
+        }
+    }
+}
+
+/// Unwraps a query parameter that must be present and well formed.
+fn required<T>(param: Option<Result<T, ApiError>>, name: &str) -> Result<T, ApiError> {
+    param.unwrap_or_else(|| {
+        let reason = format!("Missing query parameter `{}`.", name);
+        Err(error(Status::BadRequest, &reason))
+    })
+}
+
+/// Unwraps a query parameter that may be absent but must be well formed
+/// when given.
+fn optional<T, E>(param: Option<Result<T, E>>, name: &str) -> Result<Option<T>, ApiError> {
+    match param {
+        Some(Ok(value)) => Ok(Some(value)),
+        Some(Err(_)) => {
+            let reason = format!("Invalid value for query parameter `{}`.", name);
+            Err(error(Status::BadRequest, &reason))
+        }
+        None => Ok(None),
+    }
+}
+
+/// An RFC 3339 timestamp taken from the query string.
+struct Timestamp(DateTime<Utc>);
+
+impl<'v> FromFormValue<'v> for Timestamp {
+    type Error = ApiError;
+
+    fn from_form_value(form_value: &'v RawStr) -> Result<Timestamp, ApiError> {
+        let invalid = || error(Status::BadRequest, "Timestamp must be in RFC 3339 format.");
+        let value = form_value.url_decode().map_err(|_| invalid())?;
+        DateTime::parse_from_rfc3339(&value)
+            .map(|time| Timestamp(time.with_timezone(&Utc)))
+            .map_err(|_| invalid())
+    }
+}
+
+/// A todo id taken from the request path.
+///
+/// Segments that look like numbers but can't be an `ID` are rejected with
+/// `400`; anything else isn't an id at all and is treated as not found.
+struct TodoId(ID);
+
+type PathId = Result<TodoId, ApiError>;
+
+impl<'a> FromParam<'a> for TodoId {
+    type Error = ApiError;
+
+    fn from_param(param: &'a RawStr) -> Result<TodoId, ApiError> {
+        if let Ok(id) = param.parse::<ID>() {
+            return Ok(TodoId(id));
+        }
+
+        let digits = param.trim_start_matches('-');
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            Err(error(Status::NotFound, "Resource was not found."))
+        } else if param.starts_with('-') {
+            Err(error(Status::BadRequest, "Todo id must not be negative."))
+        } else {
+            let reason = format!("Todo id must not exceed {}.", ID::MAX);
+            Err(error(Status::BadRequest, &reason))
+        }
+    }
+}
+
+/// The `If-Modified-Since` request header, if present and a valid HTTP-date.
+struct IfModifiedSince(DateTime<Utc>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for IfModifiedSince {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        let since = request
+            .headers()
+            .get_one("If-Modified-Since")
+            .and_then(|value| httpdate::parse_http_date(value).ok());
+
+        match since {
+            Some(time) => Outcome::Success(IfModifiedSince(DateTime::from(time))),
+            None => Outcome::Forward(()),
+        }
+    }
+}
+
+/// The `X-If-Version` request header: an update only applies if the stored
+/// todo is still at this version.
+struct IfVersion(Option<u64>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for IfVersion {
+    type Error = ApiError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ApiError> {
+        match request.headers().get_one("X-If-Version") {
+            None => Outcome::Success(IfVersion(None)),
+            Some(value) => match value.trim().parse() {
+                Ok(version) => Outcome::Success(IfVersion(Some(version))),
+                Err(_) => Outcome::Failure((
+                    Status::BadRequest,
+                    error(Status::BadRequest, "Invalid `X-If-Version` header."),
+                )),
+            },
+        }
+    }
+}
+
+/// A todo as returned by reads, along with fields the server derives from
+/// it. The derived fields are never read back from request bodies. Every
+/// response carrying a todo goes through a view, which is what keeps the
+/// `redacted_fields` out of all of them.
+struct TodoView<'a> {
+    todo: &'a Todo,
+    redacted: &'a [String],
+}
+
+impl Serialize for TodoView<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut fields = match serde_json::to_value(self.todo).map_err(serde::ser::Error::custom)? {
+            serde_json::Value::Object(fields) => fields,
+            _ => serde_json::Map::new(),
+        };
+        fields.insert("is_overdue".to_string(), is_overdue(self.todo).into());
+        for field in self.redacted {
+            fields.remove(field);
+        }
+        fields.serialize(serializer)
+    }
+}
+
+/// Stamps `completed_at` when a todo becomes completed, keeps the stamp
+/// while it stays completed and clears it when it's reopened. `before` is
+/// the stored version being replaced, if any.
+fn track_completion(todo: &mut Todo, before: Option<&Todo>) {
+    todo.completed_at = match before {
+        _ if !todo.completed => None,
+        Some(before) if before.completed => before.completed_at,
+        _ => Some(todo.updated_at),
+    };
+}
+
+/// Archives todos completed before `cutoff`, journaling each through `log`
+/// before it changes. Todos completed before completion was stamped go by
+/// their last update instead.
+fn archive_completed<F>(
+    todos: &mut HashMap<ID, Todo>,
+    cutoff: DateTime<Utc>,
+    mut log: F,
+) -> Result<usize, ApiError>
+where
+    F: FnMut(&Todo) -> Result<(), ApiError>,
+{
+    let now = Utc::now();
+    let mut archived = 0;
+    for content in todos.values_mut() {
+        let completed_at = content.completed_at.unwrap_or(content.updated_at);
+        if !content.completed || content.archived_at.is_some() || completed_at >= cutoff {
+            continue;
+        }
+        let mut todo = content.clone();
+        todo.archived_at = Some(now);
+        todo.updated_at = now;
+        todo.version += 1;
+        log(&todo)?;
+        *content = todo;
+        archived += 1;
+    }
+    Ok(archived)
+}
+
+fn is_overdue(todo: &Todo) -> bool {
+    !todo.completed && todo.due_date.map_or(false, |due| due < Utc::now())
+}
+
+impl<'a> TodoView<'a> {
+    fn new(todo: &'a Todo, settings: &'a Settings) -> TodoView<'a> {
+        TodoView {
+            todo,
+            redacted: &settings.redacted_fields,
+        }
+    }
+}
+
+fn views<'a>(todos: &[&'a Todo], settings: &'a Settings) -> Vec<TodoView<'a>> {
+    todos
+        .iter()
+        .map(|todo| TodoView::new(todo, settings))
+        .collect()
+}
+
+/// Responds with MessagePack when the client prefers it, JSON otherwise.
+struct Negotiated<T>(T);
+
+impl<'r, T: Serialize> Responder<'r> for Negotiated<T> {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let wants_msgpack = request
+            .accept()
+            .map_or(false, |accept| accept.preferred().media_type().is_msgpack());
+        let mut response = if wants_msgpack {
+            let bytes =
+                rmp_serde::to_vec_named(&self.0).map_err(|_| Status::InternalServerError)?;
+            Response::build()
+                .header(ContentType::MsgPack)
+                .sized_body(Cursor::new(bytes))
+                .finalize()
+        } else {
+            Json(self.0).respond_to(request)?
+        };
+        add_vary(&mut response, "Accept");
+        Ok(response)
+    }
+}
+
+/// A request body in either JSON or MessagePack, chosen by `Content-Type`.
+/// Other content types are forwarded, as a `format` route attribute would,
+/// unless `lenient_content_type` lets untyped and `+json` bodies through.
+/// Bodies sent with `Content-Encoding: gzip` are decompressed first; other
+/// encodings fail with `415`. With `strict_fields`, fields the target type
+/// doesn't know fail with `400` instead of being ignored.
+struct Body<T>(T);
+
+/// Why a request body was rejected, for the `400` catcher to report.
+struct BodyError(Option<String>);
+
+fn decode<V: DeserializeOwned>(is_json: bool, reader: impl Read) -> Result<V, String> {
+    if is_json {
+        serde_json::from_reader(reader).map_err(|e| e.to_string())
+    } else {
+        rmp_serde::from_read(reader).map_err(|e| e.to_string())
+    }
+}
+
+/// The first field of `input` missing from `known`, the same value
+/// reserialized after parsing, which only keeps the fields its type has.
+fn unknown_field(input: &serde_json::Value, known: &serde_json::Value) -> Option<String> {
+    use serde_json::Value::{Array, Object};
+    match (input, known) {
+        (Object(input), Object(known)) => {
+            input.keys().find(|key| !known.contains_key(*key)).cloned()
+        }
+        (Array(input), Array(known)) => input
+            .iter()
+            .zip(known)
+            .find_map(|(input, known)| unknown_field(input, known)),
+        _ => None,
+    }
+}
+
+fn decode_strict<T: DeserializeOwned + Serialize>(
+    is_json: bool,
+    reader: impl Read,
+) -> Result<T, String> {
+    let value: serde_json::Value = decode(is_json, reader)?;
+    let parsed = T::deserialize(&value).map_err(|e| e.to_string())?;
+    let known = serde_json::to_value(&parsed).map_err(|e| e.to_string())?;
+    match unknown_field(&value, &known) {
+        Some(field) => Err(format!("Unknown field `{}`.", field)),
+        None => Ok(parsed),
+    }
+}
+
+impl<T: DeserializeOwned + Serialize> FromDataSimple for Body<T> {
+    type Error = String;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, String> {
+        let (strict, lenient) = match request.guard::<State<Settings>>() {
+            Outcome::Success(settings) => (settings.strict_fields, settings.lenient_content_type),
+            _ => (false, false),
+        };
+        let is_json = match request.content_type() {
+            Some(content_type) if content_type.is_json() => true,
+            Some(content_type) if content_type.is_msgpack() => false,
+            Some(content_type) if lenient && is_json_suffix(content_type) => true,
+            None if lenient => true,
+            _ => return Outcome::Forward(data),
+        };
+
+        let limit_name = if is_json { "json" } else { "msgpack" };
+        let limit = request.limits().get(limit_name).unwrap_or(1 << 20);
+        let reader: Box<dyn Read> = match request.headers().get_one("Content-Encoding") {
+            None => Box::new(data.open()),
+            Some(encoding) if encoding.eq_ignore_ascii_case("identity") => Box::new(data.open()),
+            Some(encoding) if encoding.eq_ignore_ascii_case("gzip") => {
+                Box::new(GzDecoder::new(data.open()))
+            }
+            Some(encoding) => {
+                let reason = format!("Unsupported content encoding `{}`.", encoding);
+                return Outcome::Failure((Status::UnsupportedMediaType, reason));
+            }
+        };
+        // The limit applies after decompression so small bodies can't
+        // expand without bound.
+        let reader = reader.take(limit);
+        let parsed = if strict {
+            decode_strict(is_json, reader)
+        } else {
+            decode(is_json, reader)
+        };
+
+        match parsed {
+            Ok(value) => Outcome::Success(Body(value)),
+            Err(e) => {
+                request.local_cache(|| BodyError(Some(e.clone())));
+                Outcome::Failure((Status::BadRequest, e))
+            }
+        }
+    }
+}
+
+/// A `Body` that may be left out. Only an empty request body counts as
+/// missing; one that is sent but doesn't parse still fails with `400`.
+struct OptionalBody<T>(Option<T>);
+
+impl<T: DeserializeOwned + Serialize> FromDataSimple for OptionalBody<T> {
+    type Error = String;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, String> {
+        if data.peek_complete() && data.peek().is_empty() {
+            return Outcome::Success(OptionalBody(None));
+        }
+        Body::from_data(request, data).map(|body| OptionalBody(Some(body.0)))
+    }
+}
+
+#[derive(Responder)]
+enum TodoResponse {
+    #[response(status = 304)]
+    NotModified(()),
+    Found(
+        Negotiated<serde_json::Value>,
+        Header<'static>,
+        Header<'static>,
+    ),
+}
+
+fn last_modified(todo: &Todo) -> Header<'static> {
+    let time = SystemTime::from(todo.updated_at);
+    Header::new("Last-Modified", httpdate::fmt_http_date(time))
+}
+
+/// A strong entity tag, which changes whenever the todo's version does.
+fn etag(todo: &Todo) -> String {
+    format!("\"{}-{}\"", todo.id, todo.version)
+}
+
+/// The `If-Match` request header: the entity tags a write is conditional on.
+struct IfMatch(Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for IfMatch {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        let value = request.headers().get_one("If-Match");
+        Outcome::Success(IfMatch(value.map(|value| value.to_string())))
+    }
+}
+
+impl IfMatch {
+    /// Whether `todo` satisfies the precondition; no header always does.
+    fn matches(&self, todo: &Todo) -> bool {
+        let value = match self.0 {
+            Some(ref value) => value,
+            None => return true,
+        };
+        let current = etag(todo);
+        value
+            .split(',')
+            .map(str::trim)
+            .any(|tag| tag == "*" || tag == current)
+    }
+}
+
+/// Whether a todo shows up in listings, which archived todos don't until
+/// restored.
+fn is_live(todo: &Todo) -> bool {
+    todo.archived_at.is_none()
+}
+
+/// The todos every listing and aggregate works from: all but the archived.
+fn live_todos(todos: &HashMap<ID, Todo>) -> impl Iterator<Item = &Todo> {
+    todos.values().filter(|todo| is_live(todo))
+}
+
+/// Conditions a todo must all meet to be listed by the index.
+struct TodoFilter {
+    priority: Option<Priority>,
+    completed: Option<bool>,
+    tag: Option<String>,
+    q: Option<String>,
+}
+
+impl TodoFilter {
+    fn is_empty(&self) -> bool {
+        self.priority.is_none()
+            && self.completed.is_none()
+            && self.tag.is_none()
+            && self.q.is_none()
+    }
+
+    fn matches(&self, todo: &Todo) -> bool {
+        self.priority
+            .map_or(true, |priority| todo.priority == priority)
+            && self
+                .completed
+                .map_or(true, |completed| todo.completed == completed)
+            && self
+                .tag
+                .as_ref()
+                .map_or(true, |tag| todo.tags.contains(tag))
+            && self.q.as_ref().map_or(true, |q| {
+                todo.title.to_lowercase().contains(&q.to_lowercase())
+            })
+    }
+}
+
+/// A JSON listing, with RFC 5988 `Link`s to its neighbouring pages when
+/// paginated.
+struct Listing(String, Option<String>);
+
+impl<'r> Responder<'r> for Listing {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let mut response = content::Json(self.0).respond_to(request)?;
+        if let Some(links) = self.1 {
+            response.set_raw_header("Link", links);
+        }
+        Ok(response)
+    }
+}
+
+const DEFAULT_PER_PAGE: usize = 20;
+
+/// The request's URL with `page` set to `page`, other parameters kept.
+fn page_url(uri: &Origin, page: usize) -> String {
+    let mut query: Vec<String> = uri
+        .query()
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .filter(|pair| !pair.is_empty() && !pair.starts_with("page="))
+        .map(|pair| pair.to_string())
+        .collect();
+    query.push(format!("page={}", page));
+    format!("{}?{}", uri.path(), query.join("&"))
+}
+
+/// `Link` values for the pages around `page`, leaving out `prev` on the
+/// first page and `next` on the last.
+fn page_links(uri: &Origin, page: usize, pages: usize) -> Option<String> {
+    let mut links = Vec::new();
+    if page > 1 {
+        links.push(format!("<{}>; rel=\"prev\"", page_url(uri, page - 1)));
+    }
+    if page < pages {
+        links.push(format!("<{}>; rel=\"next\"", page_url(uri, page + 1)));
+    }
+    if links.is_empty() {
+        None
+    } else {
+        Some(links.join(", "))
+    }
+}
+
+#[get(
+    "/?<priority>&<completed>&<tag>&<q>&<sort>&<page>&<per_page>",
+    format = "json"
+)]
+#[allow(clippy::too_many_arguments)]
+fn index(
+    priority: Option<Result<Priority, &RawStr>>,
+    completed: Option<Result<bool, &RawStr>>,
+    tag: Option<String>,
+    q: Option<String>,
+    sort: Option<Result<SortOrder, &RawStr>>,
+    page: Option<Result<usize, &RawStr>>,
+    per_page: Option<Result<usize, &RawStr>>,
+    uri: &Origin,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+    cache: State<IndexCache>,
+) -> Result<Listing, ApiError> {
+    let filter = TodoFilter {
+        priority: optional(priority, "priority")?,
+        completed: optional(completed, "completed")?,
+        tag,
+        q,
+    };
+    if let Some(priority) = filter.priority {
+        settings.check_priority(priority)?;
+    }
+    let sort = optional(sort, "sort")?;
+    let page = optional(page, "page")?;
+    let per_page = optional(per_page, "per_page")?;
+    let paginated = page.is_some() || per_page.is_some();
+    let page = page.unwrap_or(1);
+    let per_page = per_page.unwrap_or(DEFAULT_PER_PAGE);
+    if page == 0 || per_page == 0 {
+        return Err(error(
+            Status::BadRequest,
+            "Query parameters `page` and `per_page` must be positive.",
+        ));
+    }
+    // Only the default listing is cached.
+    let cacheable = filter.is_empty() && sort.is_none() && !paginated;
+    if cacheable {
+        if let Some(body) = cache.get() {
+            return Ok(Listing(body, None));
+        }
+    }
+
+    let hashmap = todos.read()?;
+    let mut matching: Vec<&Todo> = Vec::new();
+
+    for v in live_todos(&hashmap) {
+        if filter.matches(v) {
+            matching.push(v)
+        }
+    }
+    sort.unwrap_or(settings.default_sort).sort(&mut matching);
+    let mut links = None;
+    if paginated {
+        let pages = cmp::max(1, matching.len().div_ceil(per_page));
+        links = page_links(uri, page, pages);
+        matching = matching
+            .into_iter()
+            .skip((page - 1).saturating_mul(per_page))
+            .take(per_page)
+            .collect();
+    }
+    let body = serde_json::to_string(&views(&matching, &settings))?;
+    // Cached while the read lock is held, so no write can land in between.
+    if cacheable {
+        cache.put(&body);
+    }
+    Ok(Listing(body, links))
+}
+
+#[get("/<id>")]
+fn get_single_todo(
+    id: PathId,
+    since: Option<IfModifiedSince>,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<TodoResponse, ApiError> {
+    let id = id?.0;
+    let hashmap = todos.read()?;
+    let content = hashmap.get(&id).ok_or_else(|| todo_not_found(id))?;
+    // HTTP-dates only carry whole seconds, so compare at that precision.
+    Ok(match since {
+        Some(IfModifiedSince(since)) if content.updated_at.timestamp() <= since.timestamp() => {
+            TodoResponse::NotModified(())
+        }
+        _ => {
+            let view = serde_json::to_value(TodoView::new(content, &settings))?;
+            let etag = Header::new("ETag", etag(content));
+            TodoResponse::Found(Negotiated(view), last_modified(content), etag)
+        }
+    })
+}
+
+#[get("/<id>/meta", format = "json")]
+fn get_todo_meta(id: PathId, todos: State<TodoRepository>) -> Result<JsonValue, ApiError> {
+    let id = id?.0;
+    let hashmap = todos.read()?;
+    let content = hashmap.get(&id).ok_or_else(|| todo_not_found(id))?;
+    Ok(json!({
+        "id": content.id,
+        "version": content.version,
+        "created_at": content.created_at,
+        "updated_at": content.updated_at,
+        "etag": etag(content),
+    }))
+}
+
+/// The lowercase words of a title.
+fn title_tokens(title: &str) -> BTreeSet<String> {
+    title
+        .split_whitespace()
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+/// Jaccard similarity of two titles' words, from `0.0` to `1.0`.
+fn title_similarity(a: &BTreeSet<String>, b: &BTreeSet<String>) -> f64 {
+    let union = a.union(b).count();
+    if union == 0 {
+        return 0.0;
+    }
+    a.intersection(b).count() as f64 / union as f64
+}
+
+/// How many todos `/<id>/similar` returns at most.
+const MAX_SIMILAR: usize = 5;
+
+/// A todo's parents up to the root, closest first. The walk stops at a
+/// missing parent, or where a parent cycle comes back around.
+#[get("/<id>/ancestors", format = "json")]
+fn todo_ancestors(
+    id: PathId,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<JsonValue, ApiError> {
+    let id = id?.0;
+    let hashmap = todos.read()?;
+    let mut todo = hashmap.get(&id).ok_or_else(|| todo_not_found(id))?;
+    let mut visited = BTreeSet::new();
+    visited.insert(id);
+    let mut ancestors = Vec::new();
+    while let Some(parent) = todo.parent_id.and_then(|parent_id| hashmap.get(&parent_id)) {
+        if !visited.insert(parent.id) {
+            break;
+        }
+        ancestors.push(parent);
+        todo = parent;
+    }
+    Ok(json!(views(&ancestors, &settings)))
+}
+
+/// Other todos sharing words with this one's title, most similar first.
+#[get("/<id>/similar", format = "json")]
+fn similar_todos(
+    id: PathId,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<JsonValue, ApiError> {
+    let id = id?.0;
+    let hashmap = todos.read()?;
+    let todo = hashmap.get(&id).ok_or_else(|| todo_not_found(id))?;
+    let tokens = title_tokens(&todo.title);
+
+    let mut scored: Vec<(f64, &Todo)> = live_todos(&hashmap)
+        .filter(|other| other.id != id)
+        .map(|other| {
+            (
+                title_similarity(&tokens, &title_tokens(&other.title)),
+                other,
+            )
+        })
+        .filter(|&(score, _)| score > 0.0)
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.id.cmp(&b.1.id)));
+    let similar: Vec<&Todo> = scored
+        .into_iter()
+        .take(MAX_SIMILAR)
+        .map(|(_, todo)| todo)
+        .collect();
+    Ok(json!(views(&similar, &settings)))
+}
+
+/// A todo as an iCalendar file, offered for download.
+#[derive(Responder)]
+struct Calendar(content::Content<String>, Header<'static>);
+
+/// Escapes an iCalendar TEXT value (RFC 5545, section 3.3.11).
+fn ics_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+fn ics_time(time: DateTime<Utc>) -> String {
+    time.format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Exports a todo as a VEVENT starting at its due date.
+#[get("/<id>/ics")]
+fn get_todo_ics(id: PathId, todos: State<TodoRepository>) -> Result<Calendar, ApiError> {
+    let id = id?.0;
+    let hashmap = todos.read()?;
+    let todo = hashmap.get(&id).ok_or_else(|| todo_not_found(id))?;
+    let due_date = todo.due_date.ok_or_else(|| {
+        error(
+            Status::BadRequest,
+            "Todo has no due date to put on a calendar.",
+        )
+    })?;
+
+    let lines = [
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//todo//EN".to_string(),
+        "BEGIN:VEVENT".to_string(),
+        format!("UID:todo-{}", todo.id),
+        format!("DTSTAMP:{}", ics_time(todo.updated_at)),
+        format!("DTSTART:{}", ics_time(due_date)),
+        format!("SUMMARY:{}", ics_text(&todo.title)),
+        "END:VEVENT".to_string(),
+        "END:VCALENDAR".to_string(),
+    ];
+    let mut body = lines.join("\r\n");
+    body.push_str("\r\n");
+
+    let disposition = format!("attachment; filename=\"todo-{}.ics\"", todo.id);
+    Ok(Calendar(
+        content::Content(ContentType::Calendar, body),
+        Header::new("Content-Disposition", disposition),
+    ))
+}
+
+/// Extracts a single field of a todo by JSON Pointer, e.g.
+/// `/1/json-pointer/title`.
+#[get("/<id>/json-pointer/<pointer..>", format = "json")]
+fn get_todo_field(
+    id: PathId,
+    pointer: Segments,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<Option<JsonValue>, ApiError> {
+    let id = id?.0;
+    let hashmap = todos.read()?;
+    let value = match hashmap.get(&id) {
+        Some(todo) => serde_json::to_value(TodoView::new(todo, &settings))?,
+        None => return Ok(None),
+    };
+    let pointer = pointer.fold(String::new(), |pointer, segment| pointer + "/" + segment);
+    Ok(value.pointer(&pointer).cloned().map(JsonValue))
+}
+
+/// Orders todos by priority, highest first, then by weight, heaviest
+/// first, then by id.
+fn by_priority(a: &&Todo, b: &&Todo) -> cmp::Ordering {
+    b.priority
+        .cmp(&a.priority)
+        .then(b.weight.total_cmp(&a.weight))
+        .then(a.id.cmp(&b.id))
+}
+
+#[get("/board", format = "json")]
+fn board(todos: State<TodoRepository>, settings: State<Settings>) -> Result<JsonValue, ApiError> {
+    let hashmap = todos.read()?;
+    let (mut completed, mut pending): (Vec<&Todo>, Vec<&Todo>) =
+        live_todos(&hashmap).partition(|todo| todo.completed);
+
+    pending.sort_by(by_priority);
+    completed.sort_by(by_priority);
+    Ok(json!({ "pending": views(&pending, &settings), "completed": views(&completed, &settings) }))
+}
+
+/// Deployment-specific business rules applied to every created or updated
+/// todo, after the built-in checks pass.
+trait TodoValidator: Send + Sync {
+    fn validate(&self, todo: &Todo) -> Result<(), String>;
+}
+
+/// The validator used unless another is managed before the app is built.
+struct AcceptAll;
+
+impl TodoValidator for AcceptAll {
+    fn validate(&self, _todo: &Todo) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+struct Validator(Box<dyn TodoValidator>);
+
+fn is_hex_color(color: &str) -> bool {
+    color.len() == 7 && color.starts_with('#') && color[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// The most metadata a todo may carry, measured as serialized JSON.
+const MAX_METADATA_BYTES: usize = 4096;
+
+/// Normalizes a todo received from a client and lists what makes it unfit
+/// to store, by field, in the order the rules are checked. The configured
+/// validator only runs on todos that pass the built-in rules, under `todo`.
+fn todo_problems(
+    todo: &mut Todo,
+    settings: &Settings,
+    validator: &Validator,
+) -> Vec<(&'static str, String)> {
+    let mut problems = Vec::new();
+    if let Some(reason) = settings.priority_problem(todo.priority) {
+        problems.push(("priority", reason));
+    }
+    todo.title = todo.title.trim().to_string();
+    if todo.title.is_empty() {
+        problems.push(("title", "Title must not be empty.".to_string()));
+    } else if todo.title.chars().count() > settings.max_title_length {
+        let reason = format!(
+            "Title must be at most {} characters.",
+            settings.max_title_length
+        );
+        problems.push(("title", reason));
+    } else if let Some(ref prefix) = settings.title_prefix {
+        if !todo.title.starts_with(prefix.as_str()) {
+            let reason = format!("Title must start with `{}`.", prefix);
+            problems.push(("title", reason));
+        }
+    }
+    if !todo.weight.is_finite() {
+        problems.push(("weight", "Weight must be a finite number.".to_string()));
+    }
+    let mut seen = BTreeSet::new();
+    todo.tags.retain(|tag| seen.insert(tag.clone()));
+    if todo.tags.len() > settings.max_tags {
+        let reason = format!("A todo can have at most {} tags.", settings.max_tags);
+        problems.push(("tags", reason));
+    }
+    if todo.id != 0 && todo.parent_id == Some(todo.id) {
+        let reason = "A todo can't be its own parent.".to_string();
+        problems.push(("parent_id", reason));
+    }
+    if let Some(ref color) = todo.color {
+        if !is_hex_color(color) {
+            let reason = "Color must be of the form `#RRGGBB`.".to_string();
+            problems.push(("color", reason));
+        }
+    }
+    let metadata_size = serde_json::to_string(&todo.metadata).map_or(0, |json| json.len());
+    if metadata_size > MAX_METADATA_BYTES {
+        let reason = format!(
+            "Metadata must be at most {} bytes of JSON.",
+            MAX_METADATA_BYTES
+        );
+        problems.push(("metadata", reason));
+    }
+    if problems.is_empty() {
+        if let Err(reason) = validator.0.validate(todo) {
+            problems.push(("todo", reason));
+        }
+    }
+    problems
+}
+
+/// Normalizes a todo received from a client and checks it is fit to store,
+/// failing with the first problem found.
+fn validate_todo(
+    todo: &mut Todo,
+    settings: &Settings,
+    validator: &Validator,
+) -> Result<(), ApiError> {
+    match todo_problems(todo, settings, validator).into_iter().next() {
+        Some((_, reason)) => Err(error(Status::BadRequest, &reason)),
+        None => Ok(()),
+    }
+}
+
+#[get("/top?<n>", format = "json")]
+fn top_todos(
+    n: Option<Result<usize, &RawStr>>,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<JsonValue, ApiError> {
+    let n = optional(n, "n")?.unwrap_or(5);
+    if n == 0 {
+        return Err(error(
+            Status::BadRequest,
+            "Query parameter `n` must be positive.",
+        ));
+    }
+
+    let hashmap = todos.read()?;
+    let mut pending: Vec<&Todo> = live_todos(&hashmap)
+        .filter(|todo| !todo.completed)
+        .collect();
+    pending.sort_by(by_priority);
+    pending.truncate(n);
+    Ok(json!(views(&pending, &settings)))
+}
+
+/// Pending todos due within the next `days` days (a week by default),
+/// soonest first.
+#[get("/upcoming?<days>", format = "json")]
+fn upcoming_todos(
+    days: Option<Result<i64, &RawStr>>,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<JsonValue, ApiError> {
+    let days = optional(days, "days")?.unwrap_or(7);
+    if days <= 0 {
+        return Err(error(
+            Status::BadRequest,
+            "Query parameter `days` must be positive.",
+        ));
+    }
+
+    let now = Utc::now();
+    let until = chrono::Duration::try_days(days)
+        .and_then(|offset| now.checked_add_signed(offset))
+        .ok_or_else(|| error(Status::BadRequest, "`days` is out of range."))?;
+    let hashmap = todos.read()?;
+    let mut upcoming: Vec<&Todo> = live_todos(&hashmap)
+        .filter(|todo| !todo.completed)
+        .filter(|todo| match todo.due_date {
+            Some(due_date) => now <= due_date && due_date <= until,
+            None => false,
+        })
+        .collect();
+    upcoming.sort_by_key(|todo| (todo.due_date, todo.id));
+    Ok(json!(views(&upcoming, &settings)))
+}
+
+/// Pending todos created more than `days` days ago, oldest first.
+#[get("/stale?<days>", format = "json")]
+fn stale_todos(
+    days: Option<Result<i64, &RawStr>>,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<JsonValue, ApiError> {
+    let days = optional(days, "days")?.unwrap_or(30);
+    if days <= 0 {
+        return Err(error(
+            Status::BadRequest,
+            "Query parameter `days` must be positive.",
+        ));
+    }
+
+    let before = chrono::Duration::try_days(days)
+        .and_then(|offset| Utc::now().checked_sub_signed(offset))
+        .ok_or_else(|| error(Status::BadRequest, "`days` is out of range."))?;
+    let hashmap = todos.read()?;
+    let mut stale: Vec<&Todo> = live_todos(&hashmap)
+        .filter(|todo| !todo.completed && todo.created_at < before)
+        .collect();
+    SortOrder::Created.sort(&mut stale);
+    Ok(json!(views(&stale, &settings)))
+}
+
+/// Pending todos without a due date, most urgent first, for triage.
+#[get("/unscheduled", format = "json")]
+fn unscheduled_todos(
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<JsonValue, ApiError> {
+    let hashmap = todos.read()?;
+    let mut unscheduled: Vec<&Todo> = live_todos(&hashmap)
+        .filter(|todo| !todo.completed && todo.due_date.is_none())
+        .collect();
+    SortOrder::Priority.sort(&mut unscheduled);
+    Ok(json!(views(&unscheduled, &settings)))
+}
+
+/// Groups todos whose titles match once trimmed and lowercased, leaving
+/// out titles no other todo shares.
+#[get("/duplicates", format = "json")]
+fn duplicate_todos(todos: State<TodoRepository>) -> Result<JsonValue, ApiError> {
+    let hashmap = todos.read()?;
+    let mut groups: BTreeMap<String, Vec<ID>> = BTreeMap::new();
+    for todo in live_todos(&hashmap) {
+        let title = todo.title.trim().to_lowercase();
+        groups.entry(title).or_default().push(todo.id);
+    }
+    let duplicates: Vec<JsonValue> = groups
+        .into_iter()
+        .filter(|(_, ids)| ids.len() > 1)
+        .map(|(title, mut ids)| {
+            ids.sort_unstable();
+            json!({ "title": title, "ids": ids })
+        })
+        .collect();
+    Ok(json!(duplicates))
+}
+
+/// The pending todo that has waited longest, ignoring archived ones.
+#[get("/oldest-incomplete", format = "json")]
+fn oldest_incomplete(
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<JsonValue, ApiError> {
+    let hashmap = todos.read()?;
+    let oldest = live_todos(&hashmap)
+        .filter(|todo| !todo.completed)
+        .min_by_key(|todo| (todo.created_at, todo.id))
+        .ok_or_else(|| error(Status::NotFound, "No incomplete todos."))?;
+    Ok(json!(TodoView::new(oldest, &settings)))
+}
+
+/// Builds the subtree under `todo`, skipping todos already placed so a
+/// parent cycle can't recurse forever.
+fn subtree(
+    todo: &Todo,
+    children: &HashMap<ID, Vec<&Todo>>,
+    placed: &mut BTreeSet<ID>,
+    settings: &Settings,
+) -> JsonValue {
+    placed.insert(todo.id);
+    let mut nodes = Vec::new();
+    for child in children.get(&todo.id).into_iter().flatten() {
+        if !placed.contains(&child.id) {
+            nodes.push(subtree(child, children, placed, settings));
+        }
+    }
+    json!({ "todo": TodoView::new(todo, settings), "children": nodes })
+}
+
+/// Todos nested under their parents, starting from those without one.
+#[get("/tree", format = "json")]
+fn todo_tree(
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<JsonValue, ApiError> {
+    let hashmap = todos.read()?;
+    let mut sorted: Vec<&Todo> = live_todos(&hashmap).collect();
+    sorted.sort_by_key(|todo| todo.id);
+
+    let mut children: HashMap<ID, Vec<&Todo>> = HashMap::new();
+    let mut roots = Vec::new();
+    for &todo in &sorted {
+        match todo.parent_id {
+            Some(parent_id) if hashmap.get(&parent_id).map_or(false, is_live) => {
+                children.entry(parent_id).or_default().push(todo)
+            }
+            _ => roots.push(todo),
+        }
+    }
+
+    let mut placed = BTreeSet::new();
+    let mut tree: Vec<JsonValue> = roots
+        .into_iter()
+        .map(|root| subtree(root, &children, &mut placed, &settings))
+        .collect();
+    // Todos in a parent cycle are unreachable from any root; list each
+    // cycle from its lowest id instead of dropping it.
+    for &todo in &sorted {
+        if !placed.contains(&todo.id) {
+            tree.push(subtree(todo, &children, &mut placed, &settings));
+        }
+    }
+    Ok(json!(tree))
+}
+
+/// The todo list as a Markdown checklist, most urgent first.
+#[get("/export/markdown")]
+fn export_markdown(todos: State<TodoRepository>) -> Result<content::Content<String>, ApiError> {
+    let hashmap = todos.read()?;
+    let mut sorted: Vec<&Todo> = live_todos(&hashmap).collect();
+    sorted.sort_by(by_priority);
+
+    let mut markdown = String::new();
+    for todo in sorted {
+        let mark = if todo.completed { "x" } else { " " };
+        markdown.push_str(&format!(
+            "- [{}] {} (priority {})\n",
+            mark, todo.title, todo.priority.0
+        ));
+    }
+    Ok(content::Content(
+        ContentType::new("text", "markdown"),
+        markdown,
+    ))
+}
+
+/// A body served whole, or in part when the request sends a `Range` header,
+/// so large downloads can be resumed. The body's ETag is sent along, and an
+/// `If-Range` naming any other tag gets the whole current body instead.
+struct Ranged(ContentType, Vec<u8>);
+
+/// A strong entity tag for `bytes`, derived from their contents.
+fn body_etag(bytes: &[u8]) -> String {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    format!("\"{:016x}\"", hasher.finish())
+}
+
+/// Resolves a single `bytes=` range against a body of `len` bytes into a
+/// half-open span. `None` means the header isn't a range we serve, so the
+/// whole body is sent; `Some(None)` means the range lies outside the body.
+fn byte_range(header: &str, len: usize) -> Option<Option<(usize, usize)>> {
+    let spec = header.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        ("", suffix) => (len.saturating_sub(suffix.parse().ok()?), len),
+        (start, "") => (start.parse().ok()?, len),
+        (start, end) => {
+            let (start, end): (usize, usize) = (start.parse().ok()?, end.parse().ok()?);
+            if start > end {
+                return None;
+            }
+            (start, cmp::min(end.saturating_add(1), len))
+        }
+    };
+    Some(if start < end {
+        Some((start, end))
+    } else {
+        None
+    })
+}
+
+impl<'r> Responder<'r> for Ranged {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let Ranged(content_type, bytes) = self;
+        let len = bytes.len();
+        let etag = body_etag(&bytes);
+        let unchanged = request
+            .headers()
+            .get_one("If-Range")
+            .map_or(true, |tag| tag.trim() == etag);
+        let range = request
+            .headers()
+            .get_one("Range")
+            .filter(|_| unchanged)
+            .and_then(|header| byte_range(header, len));
+
+        let mut response = Response::build();
+        response
+            .header(content_type)
+            .raw_header("Accept-Ranges", "bytes")
+            .raw_header("ETag", etag);
+        match range {
+            None => response.sized_body(Cursor::new(bytes)),
+            Some(Some((start, end))) => response
+                .status(Status::PartialContent)
+                .raw_header(
+                    "Content-Range",
+                    format!("bytes {}-{}/{}", start, end - 1, len),
+                )
+                .sized_body(Cursor::new(bytes[start..end].to_vec())),
+            Some(None) => response
+                .status(Status::RangeNotSatisfiable)
+                .raw_header("Content-Range", format!("bytes */{}", len)),
+        };
+        response.ok()
+    }
+}
+
+/// Every todo as one JSON array, by id. Supports byte ranges.
+#[get("/export.json")]
+fn export_json(
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<Ranged, ApiError> {
+    let hashmap = todos.read()?;
+    let mut sorted: Vec<&Todo> = live_todos(&hashmap).collect();
+    SortOrder::Id.sort(&mut sorted);
+    let body = serde_json::to_string(&views(&sorted, &settings))?;
+    Ok(Ranged(ContentType::JSON, body.into_bytes()))
+}
+
+/// Quotes a DOT ID so any title can be used as a label.
+fn dot_string(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// The task tree as a GraphViz graph: a node per todo, labelled with its
+/// title, and an edge from each parent to its sub-tasks.
+#[get("/graph.dot")]
+fn graph_dot(todos: State<TodoRepository>) -> Result<content::Content<String>, ApiError> {
+    let hashmap = todos.read()?;
+    let mut sorted: Vec<&Todo> = live_todos(&hashmap).collect();
+    SortOrder::Id.sort(&mut sorted);
+
+    let mut dot = String::from("digraph todos {\n");
+    for todo in &sorted {
+        dot.push_str(&format!(
+            "  {} [label={}];\n",
+            todo.id,
+            dot_string(&todo.title)
+        ));
+    }
+    for todo in &sorted {
+        if let Some(parent_id) = todo.parent_id {
+            if hashmap.get(&parent_id).map_or(false, is_live) {
+                dot.push_str(&format!("  {} -> {};\n", parent_id, todo.id));
+            }
+        }
+    }
+    dot.push_str("}\n");
+    Ok(content::Content(
+        ContentType::new("text", "vnd.graphviz"),
+        dot,
+    ))
+}
+
+/// Every tag in use with how many todos carry it, most used first.
+#[get("/tags", format = "json")]
+fn list_tags(todos: State<TodoRepository>) -> Result<JsonValue, ApiError> {
+    let hashmap = todos.read()?;
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for tag in live_todos(&hashmap).flat_map(|todo| &todo.tags) {
+        *counts.entry(tag).or_insert(0) += 1;
+    }
+
+    let mut counts: Vec<(&str, usize)> = counts.into_iter().collect();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    let tags: Vec<JsonValue> = counts
+        .into_iter()
+        .map(|(tag, count)| json!({ "tag": tag, "count": count }))
+        .collect();
+    Ok(json!(tags))
+}
+
+/// How many todos carry each tag. A todo listing a tag twice counts once.
+#[get("/count/by-tag", format = "json")]
+fn count_by_tag(todos: State<TodoRepository>) -> Result<JsonValue, ApiError> {
+    let hashmap = todos.read()?;
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    for todo in live_todos(&hashmap) {
+        let tags: BTreeSet<&str> = todo.tags.iter().map(String::as_str).collect();
+        for tag in tags {
+            *counts.entry(tag).or_insert(0) += 1;
+        }
+    }
+    Ok(json!(counts))
+}
+
+/// Distinct titles starting with `prefix`, ignoring case, in alphabetical
+/// order, for typeahead.
+#[get("/autocomplete?<prefix>&<limit>", format = "json")]
+fn autocomplete(
+    prefix: Option<String>,
+    limit: Option<Result<usize, &RawStr>>,
+    todos: State<TodoRepository>,
+) -> Result<JsonValue, ApiError> {
+    let prefix = prefix.unwrap_or_default().to_lowercase();
+    if prefix.is_empty() {
+        return Err(error(
+            Status::BadRequest,
+            "Query parameter `prefix` must not be empty.",
+        ));
+    }
+    let limit = optional(limit, "limit")?.unwrap_or(5);
+
+    let hashmap = todos.read()?;
+    let titles: BTreeSet<&str> = live_todos(&hashmap)
+        .map(|todo| todo.title.as_str())
+        .filter(|title| title.to_lowercase().starts_with(&prefix))
+        .collect();
+    let mut titles: Vec<&str> = titles.into_iter().collect();
+    titles.sort_by_cached_key(|title| title.to_lowercase());
+    titles.truncate(limit);
+    Ok(json!(titles))
+}
+
+/// One owner's share of the todos, for `GET /workload`.
+#[derive(Default, Serialize)]
+struct Load<'a> {
+    owner: &'a str,
+    pending: usize,
+    completed: usize,
+    /// The sum of the pending todos' priorities.
+    total_priority: usize,
+}
+
+/// Per-owner totals, for balancing assignments, most pending first.
+/// Unassigned todos aren't counted.
+#[get("/workload", format = "json")]
+fn workload(todos: State<TodoRepository>) -> Result<JsonValue, ApiError> {
+    let hashmap = todos.read()?;
+    let mut loads: BTreeMap<&str, Load> = BTreeMap::new();
+    for todo in live_todos(&hashmap) {
+        let owner = match todo.owner {
+            Some(ref owner) => owner.as_str(),
+            None => continue,
+        };
+        let load = loads.entry(owner).or_insert_with(|| Load {
+            owner,
+            ..Load::default()
+        });
+        if todo.completed {
+            load.completed += 1;
+        } else {
+            load.pending += 1;
+            load.total_priority += todo.priority.0;
+        }
+    }
+
+    let mut loads: Vec<Load> = loads.into_values().collect();
+    // Stable, so owners with equal pending counts stay alphabetical.
+    loads.sort_by(|a, b| b.pending.cmp(&a.pending));
+    Ok(json!(loads))
+}
+
+/// The latest changes across all todos, newest first, optionally only those
+/// of one kind.
+#[get("/changes?<op>&<limit>", format = "json")]
+fn list_changes(
+    op: Option<Result<ChangeOp, &RawStr>>,
+    limit: Option<Result<usize, &RawStr>>,
+    changes: State<ChangeLog>,
+) -> Result<JsonValue, ApiError> {
+    let op = optional(op, "op")?;
+    let limit = optional(limit, "limit")?.unwrap_or(20);
+    if limit == 0 {
+        return Err(error(
+            Status::BadRequest,
+            "Query parameter `limit` must be positive.",
+        ));
+    }
+
+    let changes = changes.0.lock()?;
+    let latest: Vec<&Change> = changes
+        .iter()
+        .rev()
+        .filter(|change| op.map_or(true, |op| change.op == op))
+        .take(limit)
+        .collect();
+    Ok(json!(latest))
+}
+
+#[get("/random", format = "json")]
+fn random_todo(
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<Option<JsonValue>, ApiError> {
+    let hashmap = todos.read()?;
+    let pending: Vec<&Todo> = live_todos(&hashmap)
+        .filter(|todo| !todo.completed)
+        .collect();
+    let mut rng = StdRng::from_entropy();
+    Ok(pending
+        .choose(&mut rng)
+        .map(|todo| json!(TodoView::new(todo, &settings))))
+}
+
+#[get("/first", format = "json")]
+fn first_todo(
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<Option<JsonValue>, ApiError> {
+    let hashmap = todos.read()?;
+    Ok(live_todos(&hashmap)
+        .min_by_key(|todo| todo.id)
+        .map(|todo| json!(TodoView::new(todo, &settings))))
+}
+
+#[get("/last", format = "json")]
+fn last_todo(
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<Option<JsonValue>, ApiError> {
+    let hashmap = todos.read()?;
+    Ok(live_todos(&hashmap)
+        .max_by_key(|todo| todo.id)
+        .map(|todo| json!(TodoView::new(todo, &settings))))
+}
+
+#[get("/diff?<since>", format = "json")]
+fn diff(
+    since: Option<Result<Timestamp, ApiError>>,
+    todos: State<TodoRepository>,
+    tombstones: State<Tombstones>,
+    settings: State<Settings>,
+) -> Result<JsonValue, ApiError> {
+    let since = required(since, "since")?.0;
+    let hashmap = todos.read()?;
+    let tombstones = tombstones.read()?;
+
+    let mut changed: Vec<&Todo> = hashmap
+        .values()
+        .filter(|todo| todo.updated_at > since)
+        .collect();
+    changed.sort_by_key(|todo| todo.id);
+
+    // An id that has since been re-created shows up as changed instead.
+    let mut deleted: Vec<ID> = tombstones
+        .iter()
+        .filter(|(id, deleted_at)| **deleted_at > since && !hashmap.contains_key(id))
+        .map(|(id, _)| *id)
+        .collect();
+    deleted.sort_unstable();
+
+    Ok(json!({ "todos": views(&changed, &settings), "deleted": deleted }))
+}
+
+/// Mutating routes accept `?dry_run=true` to preview their result without
+/// touching the store.
+fn is_dry_run(dry_run: Option<Result<bool, &RawStr>>) -> Result<bool, ApiError> {
+    Ok(optional(dry_run, "dry_run")?.unwrap_or(false))
+}
+
+#[post("/?<dry_run>", data = "<todo>")]
+fn add_todo(
+    todo: Body<Todo>,
+    writable: Writable,
+    dry_run: Option<Result<bool, &RawStr>>,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+    validator: State<Validator>,
+    next_id: State<NextId>,
+) -> Result<JsonValue, ApiError> {
+    let dry_run = is_dry_run(dry_run)?;
+    let mut todo = todo.0;
+    validate_todo(&mut todo, &settings, &validator)?;
+    todo.created_at = Utc::now();
+    todo.updated_at = todo.created_at;
+    todo.version = 1;
+    track_completion(&mut todo, None);
+    if todo.due_date.is_none() {
+        if let Some(&offset) = settings.default_due.get(&todo.priority.0) {
+            todo.due_date = todo.created_at.checked_add_signed(offset);
+        }
+    }
+
+    // Assign the id while holding the lock so it can't race another insert.
+    let mut hashmap = write_store(&todos, &settings)?;
+    settings.check_unique(&todo, &hashmap)?;
+    if dry_run {
+        todo.id = next_id.peek(todo.id)?;
+        let todo = TodoView::new(&todo, &settings);
+        return Ok(json!({ "status": "ok", "dry_run": true, "todo": todo }));
+    }
+    todo.id = next_id.assign(todo.id)?;
+    writable.log(&wal::Entry::put(&todo))?;
+    let id = todo.id;
+    hashmap.insert(id, todo);
+    Ok(json!({ "status": "ok", "id": id }))
+}
+
+/// Checks a todo against the rules `POST /` enforces, without storing it.
+#[post("/validate", data = "<todo>")]
+fn validate(todo: Body<Todo>, settings: State<Settings>, validator: State<Validator>) -> JsonValue {
+    let mut todo = todo.0;
+    let problems = todo_problems(&mut todo, &settings, &validator);
+    if problems.is_empty() {
+        return json!({ "valid": true });
+    }
+    let errors: BTreeMap<&str, String> = problems.into_iter().collect();
+    json!({ "valid": false, "errors": errors })
+}
+
+#[delete("/<id>?<dry_run>", format = "json")]
+fn delete_todo(
+    id: PathId,
+    writable: Writable,
+    if_match: IfMatch,
+    dry_run: Option<Result<bool, &RawStr>>,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+    tombstones: State<Tombstones>,
+) -> Result<JsonValue, ApiError> {
+    let id = id?.0;
+    let dry_run = is_dry_run(dry_run)?;
+    let mut hashmap = write_store(&todos, &settings)?;
+    let existing = hashmap.get(&id).ok_or_else(|| todo_not_found(id))?;
+    if !if_match.matches(existing) {
+        return Err(error(
+            Status::PreconditionFailed,
+            "Todo has changed since the given ETag.",
+        ));
+    }
+    if dry_run {
+        let existing = TodoView::new(existing, &settings);
+        return Ok(json!({ "status": "ok", "dry_run": true, "deleted": existing }));
+    }
+    writable.log(&wal::Entry::Delete { id })?;
+    hashmap.remove(&id);
+    let mut tombstones = tombstones.write()?;
+    tombstones.insert(id, Utc::now());
+    Ok(json!({ "status": "ok" }))
+}
+
+#[delete("/completed", format = "json")]
+fn delete_completed(
+    writable: Writable,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+    tombstones: State<Tombstones>,
+) -> Result<JsonValue, ApiError> {
+    let mut hashmap = write_store(&todos, &settings)?;
+    let completed: Vec<ID> = hashmap
+        .values()
+        .filter(|todo| todo.completed)
+        .map(|todo| todo.id)
+        .collect();
+
+    let mut tombstones = tombstones.write()?;
+    let now = Utc::now();
+    for &id in &completed {
+        writable.log(&wal::Entry::Delete { id })?;
+        hashmap.remove(&id);
+        tombstones.insert(id, now);
+    }
+    Ok(json!({ "deleted": completed.len() }))
+}
+
+/// Archives or restores a todo. Archiving an archived todo keeps the time it
+/// was first archived.
+fn set_archived(
+    id: ID,
+    archived: bool,
+    writable: &Writable,
+    todos: &TodoRepository,
+    settings: &Settings,
+) -> Result<JsonValue, ApiError> {
+    let mut hashmap = write_store(todos, settings)?;
+    let content = hashmap.get_mut(&id).ok_or_else(|| todo_not_found(id))?;
+    if content.archived_at.is_some() == archived {
+        return Ok(json!(TodoView::new(content, settings)));
+    }
+    let mut todo = content.clone();
+    todo.updated_at = Utc::now();
+    todo.archived_at = if archived {
+        Some(todo.updated_at)
+    } else {
+        None
+    };
+    todo.version += 1;
+    writable.log(&wal::Entry::put(&todo))?;
+    *content = todo;
+    Ok(json!(TodoView::new(content, settings)))
+}
+
+#[post("/<id>/archive", format = "json")]
+fn archive_todo(
+    id: PathId,
+    writable: Writable,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<JsonValue, ApiError> {
+    set_archived(id?.0, true, &writable, &todos, &settings)
+}
+
+#[post("/<id>/restore", format = "json")]
+fn restore_todo(
+    id: PathId,
+    writable: Writable,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<JsonValue, ApiError> {
+    set_archived(id?.0, false, &writable, &todos, &settings)
+}
+
+/// Archived todos, by id.
+#[get("/archived", format = "json")]
+fn list_archived(
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<JsonValue, ApiError> {
+    let hashmap = todos.read()?;
+    let mut archived: Vec<&Todo> = hashmap
+        .values()
+        .filter(|todo| todo.archived_at.is_some())
+        .collect();
+    SortOrder::Id.sort(&mut archived);
+    Ok(json!(views(&archived, &settings)))
+}
+
+/// Permanently deletes every archived todo; they can't be restored after.
+#[delete("/archived", format = "json")]
+fn purge_archived(
+    writable: Writable,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+    tombstones: State<Tombstones>,
+) -> Result<JsonValue, ApiError> {
+    let mut hashmap = write_store(&todos, &settings)?;
+    let archived: Vec<ID> = hashmap
+        .values()
+        .filter(|todo| todo.archived_at.is_some())
+        .map(|todo| todo.id)
+        .collect();
+
+    let mut tombstones = tombstones.write()?;
+    let now = Utc::now();
+    for &id in &archived {
+        writable.log(&wal::Entry::Delete { id })?;
+        hashmap.remove(&id);
+        tombstones.insert(id, now);
+    }
+    Ok(json!({ "purged": archived.len() }))
+}
+
+#[put("/<id>?<dry_run>", data = "<todo>")]
+#[allow(clippy::too_many_arguments)]
+fn update_todo(
+    id: PathId,
+    writable: Writable,
+    if_version: Result<IfVersion, ApiError>,
+    todo: Body<Todo>,
+    dry_run: Option<Result<bool, &RawStr>>,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+    validator: State<Validator>,
+) -> Result<JsonValue, ApiError> {
+    let id = id?.0;
+    let if_version = if_version?.0;
+    let dry_run = is_dry_run(dry_run)?;
+    let mut todo = todo.0;
+    // The path decides which todo is updated, so the stored id must agree.
+    todo.id = id;
+    validate_todo(&mut todo, &settings, &validator)?;
+    todo.updated_at = Utc::now();
+    let mut hashmap = write_store(&todos, &settings)?;
+    let content = hashmap.get(&id).ok_or_else(|| todo_not_found(id))?;
+    match if_version {
+        Some(version) if version != content.version => {
+            let reason = format!("Version mismatch: todo is at version {}.", content.version);
+            return Err(error(Status::Conflict, &reason));
+        }
+        _ => {}
+    }
+    todo.created_at = content.created_at;
+    todo.version = content.version + 1;
+    track_completion(&mut todo, Some(content));
+    settings.check_unique(&todo, &hashmap)?;
+    if dry_run {
+        let todo = TodoView::new(&todo, &settings);
+        return Ok(json!({ "status": "ok", "dry_run": true, "todo": todo }));
+    }
+    writable.log(&wal::Entry::put(&todo))?;
+    hashmap.insert(id, todo);
+    Ok(json!({ "status": "ok" }))
+}
+
+/// Replaces the whole store with the given todos, all or nothing: if any
+/// todo is invalid, nothing changes.
+#[put("/", data = "<replacement>")]
+fn replace_todos(
+    writable: Writable,
+    replacement: Body<Vec<Todo>>,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+    validator: State<Validator>,
+    next_id: State<NextId>,
+    tombstones: State<Tombstones>,
+) -> Result<JsonValue, ApiError> {
+    let mut replacement = replacement.0;
+    let now = Utc::now();
+    for todo in &mut replacement {
+        validate_todo(todo, &settings, &validator)?;
+        todo.created_at = now;
+        todo.updated_at = now;
+        todo.version = 1;
+        track_completion(todo, None);
+    }
+
+    let mut hashmap = write_store(&todos, &settings)?;
+    let mut replaced = HashMap::new();
+    for mut todo in replacement {
+        todo.id = next_id.assign(todo.id)?;
+        if replaced.contains_key(&todo.id) {
+            let reason = format!("Todo id {} appears more than once.", todo.id);
+            return Err(error(Status::BadRequest, &reason));
+        }
+        settings.check_unique(&todo, &replaced)?;
+        replaced.insert(todo.id, todo);
+    }
+
+    let removed: Vec<ID> = hashmap
+        .keys()
+        .filter(|id| !replaced.contains_key(id))
+        .cloned()
+        .collect();
+    for &id in &removed {
+        writable.log(&wal::Entry::Delete { id })?;
+    }
+    for todo in replaced.values() {
+        writable.log(&wal::Entry::put(todo))?;
+    }
+
+    let mut tombstones = tombstones.write()?;
+    for id in removed {
+        tombstones.insert(id, now);
+    }
+    let count = replaced.len();
+    *hashmap = replaced;
+    Ok(json!({ "count": count }))
+}
+
+/// Adds the given todos to the store, replacing any with the same id. The
+/// merged store is built from a copy while reads carry on against the old
+/// one, then swapped in whole, so no reader sees half an import.
+#[post("/import.json", data = "<imported>")]
+fn import_json(
+    writable: Writable,
+    imported: Body<Vec<Todo>>,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+    validator: State<Validator>,
+    next_id: State<NextId>,
+    importing: State<Importing>,
+) -> Result<JsonValue, ApiError> {
+    let run = importing
+        .start()
+        .ok_or_else(|| retry_later("An import is in progress."))?;
+    let mut merged = todos.read()?.clone();
+    let now = Utc::now();
+    let mut changed = Vec::new();
+    for mut todo in imported.0 {
+        validate_todo(&mut todo, &settings, &validator)?;
+        todo.id = next_id.assign(todo.id)?;
+        settings.check_unique(&todo, &merged)?;
+        let previous = merged.get(&todo.id);
+        todo.created_at = previous.map_or(now, |previous| previous.created_at);
+        todo.updated_at = now;
+        todo.version = previous.map_or(1, |previous| previous.version + 1);
+        track_completion(&mut todo, previous);
+        changed.push(todo.id);
+        merged.insert(todo.id, todo);
+    }
+
+    let mut hashmap = write_store(&todos, &settings)?;
+    // Writes wait on the lock from here, so they'll land on the new store.
+    drop(run);
+    for id in &changed {
+        writable.log(&wal::Entry::put(&merged[id]))?;
+    }
+    *hashmap = merged;
+    Ok(json!({ "status": "ok", "count": changed.len() }))
+}
+
+/// A JSON Patch document body. Rocket's `format` only knows registered media
+/// types, so this checks for `application/json-patch+json` itself.
+struct JsonPatch(json_patch::Patch);
+
+impl FromDataSimple for JsonPatch {
+    type Error = String;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, String> {
+        match request.content_type() {
+            Some(ct) if ct.top() == "application" && ct.sub() == "json-patch+json" => {}
+            _ => return Outcome::Forward(data),
+        }
+
+        let limit = request.limits().get("json").unwrap_or(1 << 20);
+        match serde_json::from_reader(data.open().take(limit)) {
+            Ok(patch) => Outcome::Success(JsonPatch(patch)),
+            Err(e) => Outcome::Failure((Status::BadRequest, e.to_string())),
+        }
+    }
+}
+
+/// The fields that differ between two versions of a todo, in field order,
+/// as `{"field", "old", "new"}`. The bookkeeping every change touches,
+/// `updated_at` and `version`, is left out, as are the `redacted` fields.
+fn field_changes(
+    before: &Todo,
+    after: &Todo,
+    redacted: &[String],
+) -> Result<Vec<JsonValue>, ApiError> {
+    let before = serde_json::to_value(before)?;
+    let after = serde_json::to_value(after)?;
+    let (before, after) = match (before.as_object(), after.as_object()) {
+        (Some(before), Some(after)) => (before, after),
+        _ => return Ok(Vec::new()),
+    };
+    let null = serde_json::Value::Null;
+    Ok(after
+        .iter()
+        .filter(|&(field, _)| field != "updated_at" && field != "version")
+        .filter(|&(field, _)| !redacted.contains(field))
+        .filter_map(|(field, new)| {
+            let old = before.get(field).unwrap_or(&null);
+            if old == new {
+                None
+            } else {
+                Some(json!({ "field": field, "old": old, "new": new }))
+            }
+        })
+        .collect())
+}
+
+#[patch("/<id>?<dry_run>", data = "<patch>")]
+fn patch_todo(
+    id: PathId,
+    patch: JsonPatch,
+    writable: Writable,
+    dry_run: Option<Result<bool, &RawStr>>,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+    validator: State<Validator>,
+) -> Result<Option<JsonValue>, ApiError> {
+    let id = id?.0;
+    let dry_run = is_dry_run(dry_run)?;
+    let mut hashmap = write_store(&todos, &settings)?;
+    let content = match hashmap.get(&id) {
+        Some(content) => content,
+        None => return Ok(None),
+    };
+
+    let mut document = serde_json::to_value(content)?;
+    json_patch::patch(&mut document, &patch.0).map_err(|e| {
+        let reason = format!("Patch could not be applied: {}.", e);
+        error(Status::BadRequest, &reason)
+    })?;
+    let mut todo: Todo = serde_json::from_value(document).map_err(|e| {
+        let reason = format!("Patched todo is invalid: {}.", e);
+        error(Status::BadRequest, &reason)
+    })?;
+    todo.id = id;
+    validate_todo(&mut todo, &settings, &validator)?;
+    todo.created_at = content.created_at;
+    todo.updated_at = Utc::now();
+    todo.version = content.version + 1;
+    track_completion(&mut todo, Some(content));
+    settings.check_unique(&todo, &hashmap)?;
+    let changes = field_changes(content, &todo, &settings.redacted_fields)?;
+
+    if dry_run {
+        return Ok(Some(
+            json!({ "status": "ok", "dry_run": true, "todo": TodoView::new(&todo, &settings), "changes": changes }),
+        ));
+    }
+    writable.log(&wal::Entry::put(&todo))?;
+    let body =
+        json!({ "status": "ok", "todo": TodoView::new(&todo, &settings), "changes": changes });
+    hashmap.insert(id, todo);
+    Ok(Some(body))
+}
+
+#[derive(Serialize, Deserialize)]
+struct IdList {
+    ids: Vec<ID>,
+}
+
+#[patch("/bulk-complete", data = "<body>")]
+fn bulk_complete(
+    body: Body<IdList>,
+    writable: Writable,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<JsonValue, ApiError> {
+    let mut ids = body.0.ids;
+    ids.sort_unstable();
+    ids.dedup();
+
+    let mut hashmap = write_store(&todos, &settings)?;
+    let now = Utc::now();
+    let mut completed = 0;
+    let mut missing = Vec::new();
+    for id in ids {
+        match hashmap.get_mut(&id) {
+            Some(todo) => {
+                let mut done = todo.clone();
+                done.completed = true;
+                done.updated_at = now;
+                done.version += 1;
+                track_completion(&mut done, Some(todo));
+                writable.log(&wal::Entry::put(&done))?;
+                *todo = done;
+                completed += 1;
+            }
+            None => missing.push(id),
+        }
+    }
+    Ok(json!({ "completed": completed, "missing": missing }))
+}
+
+#[derive(Serialize, Deserialize)]
+struct Schedule {
+    ids: Vec<ID>,
+    in_days: i64,
+}
+
+/// Sets each listed todo's due date to `in_days` from now; a negative
+/// count backdates.
+#[post("/schedule", data = "<schedule>")]
+fn schedule_todos(
+    schedule: Body<Schedule>,
+    writable: Writable,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<JsonValue, ApiError> {
+    let Schedule { mut ids, in_days } = schedule.0;
+    ids.sort_unstable();
+    ids.dedup();
+
+    let now = Utc::now();
+    let due_date = chrono::Duration::try_days(in_days)
+        .and_then(|offset| now.checked_add_signed(offset))
+        .ok_or_else(|| error(Status::BadRequest, "`in_days` is out of range."))?;
+
+    let mut hashmap = write_store(&todos, &settings)?;
+    let mut updated = 0;
+    let mut missing = Vec::new();
+    for id in ids {
+        match hashmap.get_mut(&id) {
+            Some(todo) => {
+                let mut scheduled = todo.clone();
+                scheduled.due_date = Some(due_date);
+                scheduled.updated_at = now;
+                scheduled.version += 1;
+                writable.log(&wal::Entry::put(&scheduled))?;
+                *todo = scheduled;
+                updated += 1;
+            }
+            None => missing.push(id),
+        }
+    }
+    Ok(json!({ "updated": updated, "missing": missing }))
+}
+
+#[derive(Serialize, Deserialize)]
+struct TagChange {
+    ids: Vec<ID>,
+    #[serde(default)]
+    add: Vec<String>,
+    #[serde(default)]
+    remove: Vec<String>,
+}
+
+/// Adds and removes tags across several todos at once. If any id is
+/// missing, or any todo would end up over `max_tags`, nothing changes.
+#[post("/tag", data = "<change>")]
+fn tag_todos(
+    change: Body<TagChange>,
+    writable: Writable,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<JsonValue, ApiError> {
+    let change = change.0;
+    let mut hashmap = write_store(&todos, &settings)?;
+    if let Some(&id) = change.ids.iter().find(|id| !hashmap.contains_key(id)) {
+        return Err(todo_not_found(id));
+    }
+
+    let now = Utc::now();
+    let ids: BTreeSet<ID> = change.ids.iter().cloned().collect();
+    let mut updated = Vec::new();
+    for id in ids {
+        let content = hashmap.get(&id).ok_or_else(|| todo_not_found(id))?;
+        let mut todo = content.clone();
+        todo.tags.retain(|tag| !change.remove.contains(tag));
+        for tag in &change.add {
+            if !todo.tags.contains(tag) {
+                todo.tags.push(tag.clone());
+            }
+        }
+        if todo.tags == content.tags {
+            continue;
+        }
+        if todo.tags.len() > settings.max_tags {
+            let reason = format!("A todo can have at most {} tags.", settings.max_tags);
+            return Err(error(Status::BadRequest, &reason));
+        }
+        todo.updated_at = now;
+        todo.version += 1;
+        updated.push(todo);
+    }
+
+    let changed = updated.len();
+    for todo in updated {
+        writable.log(&wal::Entry::put(&todo))?;
+        hashmap.insert(todo.id, todo);
+    }
+    Ok(json!({ "changed": changed }))
+}
+
+/// Flips whether a todo is completed.
+#[patch("/<id>/toggle")]
+fn toggle_todo(
+    id: PathId,
+    writable: Writable,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<JsonValue, ApiError> {
+    let id = id?.0;
+    let mut hashmap = write_store(&todos, &settings)?;
+    let content = hashmap.get_mut(&id).ok_or_else(|| todo_not_found(id))?;
+    let mut todo = content.clone();
+    todo.completed = !todo.completed;
+    todo.updated_at = Utc::now();
+    track_completion(&mut todo, Some(content));
+    todo.version += 1;
+    writable.log(&wal::Entry::put(&todo))?;
+    *content = todo;
+    Ok(json!(TodoView::new(content, &settings)))
+}
+
+#[derive(Serialize, Deserialize)]
+struct CompletionNote {
+    note: String,
+}
+
+/// Completes a todo and records how, appending to its notes.
+#[post("/<id>/complete-with-note", data = "<note>")]
+fn complete_with_note(
+    id: PathId,
+    note: Body<CompletionNote>,
+    writable: Writable,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<JsonValue, ApiError> {
+    let id = id?.0;
+    let note = note.0.note.trim().to_string();
+    if note.is_empty() {
+        return Err(error(Status::BadRequest, "Note must not be empty."));
+    }
+
+    let mut hashmap = write_store(&todos, &settings)?;
+    let content = hashmap.get_mut(&id).ok_or_else(|| todo_not_found(id))?;
+    let mut todo = content.clone();
+    todo.completed = true;
+    todo.notes.push(note);
+    todo.updated_at = Utc::now();
+    track_completion(&mut todo, Some(content));
+    todo.version += 1;
+    writable.log(&wal::Entry::put(&todo))?;
+    *content = todo;
+    Ok(json!(TodoView::new(content, &settings)))
+}
+
+#[derive(Serialize, Deserialize)]
+struct Rescale {
+    factor: f64,
+}
+
+/// Sets a todo back to the configured default priority.
+#[post("/<id>/reset-priority", format = "json")]
+fn reset_priority(
+    id: PathId,
+    writable: Writable,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<JsonValue, ApiError> {
+    let id = id?.0;
+    let mut hashmap = write_store(&todos, &settings)?;
+    let content = hashmap.get_mut(&id).ok_or_else(|| todo_not_found(id))?;
+    let mut todo = content.clone();
+    todo.priority = settings.default_priority;
+    todo.updated_at = Utc::now();
+    todo.version += 1;
+    writable.log(&wal::Entry::put(&todo))?;
+    *content = todo;
+    Ok(json!(TodoView::new(content, &settings)))
+}
+
+#[derive(Serialize, Deserialize)]
+struct Reorder {
+    order: Vec<ID>,
+}
+
+/// Numbers todos' positions in the given order. Todos left out follow the
+/// listed ones in their current order, and unknown ids are ignored.
+#[post("/reorder", data = "<reorder>")]
+fn reorder_todos(
+    reorder: Body<Reorder>,
+    writable: Writable,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<JsonValue, ApiError> {
+    let mut hashmap = write_store(&todos, &settings)?;
+    let mut current: Vec<&Todo> = hashmap.values().collect();
+    SortOrder::Position.sort(&mut current);
+
+    let mut placed = BTreeSet::new();
+    let mut order: Vec<ID> = reorder
+        .0
+        .order
+        .iter()
+        .cloned()
+        .filter(|id| hashmap.contains_key(id) && placed.insert(*id))
+        .collect();
+    order.extend(
+        current
+            .iter()
+            .map(|todo| todo.id)
+            .filter(|id| !placed.contains(id)),
+    );
+
+    let now = Utc::now();
+    for (position, id) in order.iter().enumerate() {
+        let content = match hashmap.get_mut(id) {
+            Some(content) if content.position != Some(position) => content,
+            _ => continue,
+        };
+        let mut todo = content.clone();
+        todo.position = Some(position);
+        todo.updated_at = now;
+        todo.version += 1;
+        writable.log(&wal::Entry::put(&todo))?;
+        *content = todo;
+    }
+    Ok(json!({ "order": order }))
+}
+
+#[derive(Serialize, Deserialize)]
+struct Increment {
+    by: u64,
+}
+
+/// Bumps a todo's `count` by one, or by `{"by": n}` when a body is sent.
+#[post("/<id>/increment", data = "<increment>")]
+fn increment_count(
+    id: PathId,
+    increment: OptionalBody<Increment>,
+    writable: Writable,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<JsonValue, ApiError> {
+    let id = id?.0;
+    let by = increment.0.map_or(1, |increment| increment.by);
+    let mut hashmap = write_store(&todos, &settings)?;
+    let content = hashmap.get_mut(&id).ok_or_else(|| todo_not_found(id))?;
+    let mut todo = content.clone();
+    todo.count = todo
+        .count
+        .checked_add(by)
+        .ok_or_else(|| error(Status::BadRequest, "Count would overflow."))?;
+    todo.updated_at = Utc::now();
+    todo.version += 1;
+    writable.log(&wal::Entry::put(&todo))?;
+    *content = todo;
+    Ok(json!({ "id": id, "count": content.count }))
+}
+
+/// Multiplies every priority by `factor`, clamped to the configured range.
+#[post("/rescale", data = "<rescale>")]
+fn rescale_priorities(
+    rescale: Body<Rescale>,
+    writable: Writable,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<JsonValue, ApiError> {
+    let factor = rescale.0.factor;
+    if !(factor > 0.0 && factor.is_finite()) {
+        return Err(error(Status::BadRequest, "Factor must be positive."));
+    }
+
+    let mut hashmap = write_store(&todos, &settings)?;
+    let now = Utc::now();
+    let mut changed = 0;
+    for todo in hashmap.values_mut() {
+        let scaled = (todo.priority.0 as f64 * factor).round() as usize;
+        let priority = Priority(scaled.max(settings.min_priority).min(settings.max_priority));
+        if priority == todo.priority {
+            continue;
+        }
+        let mut rescaled = todo.clone();
+        rescaled.priority = priority;
+        rescaled.updated_at = now;
+        rescaled.version += 1;
+        writable.log(&wal::Entry::put(&rescaled))?;
+        *todo = rescaled;
+        changed += 1;
+    }
+    Ok(json!({ "changed": changed }))
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snooze {
+    days: i64,
+}
+
+#[post("/<id>/snooze", data = "<snooze>")]
+fn snooze_todo(
+    id: PathId,
+    snooze: Body<Snooze>,
+    writable: Writable,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<Option<JsonValue>, ApiError> {
+    let id = id?.0;
+    if snooze.0.days <= 0 {
+        return Err(error(Status::BadRequest, "Days must be positive."));
+    }
+    let out_of_range = || error(Status::BadRequest, "`days` is out of range.");
+    let offset = chrono::Duration::try_days(snooze.0.days).ok_or_else(out_of_range)?;
+
+    let mut hashmap = write_store(&todos, &settings)?;
+    let content = match hashmap.get_mut(&id) {
+        Some(content) => content,
+        None => return Ok(None),
+    };
+    let mut todo = content.clone();
+    let now = Utc::now();
+    let due_date = todo.due_date.unwrap_or(now);
+    todo.due_date = Some(
+        due_date
+            .checked_add_signed(offset)
+            .ok_or_else(out_of_range)?,
+    );
+    todo.updated_at = now;
+    todo.version += 1;
+    writable.log(&wal::Entry::put(&todo))?;
+    *content = todo.clone();
+    Ok(Some(json!(TodoView::new(&todo, &settings))))
+}
+
+/// Rewrites the write-ahead log as a snapshot of the current store.
+#[post("/compact")]
+fn compact(
+    writable: Writable,
+    todos: State<TodoRepository>,
+    settings: State<Settings>,
+) -> Result<JsonValue, ApiError> {
+    let wal = match writable.journal.0 {
+        Some(ref wal) => wal,
+        None => {
+            return Err(error(
+                Status::NotFound,
+                "Write-ahead log is not configured.",
+            ))
+        }
+    };
+    let hashmap = write_store(&todos, &settings)?;
+    wal.compact(hashmap.values()).map_err(log_failed)?;
+    Ok(json!({ "status": "ok", "todos": hashmap.len() }))
+}
+
+/// Copies the current store into the snapshot `name`, replacing any
+/// earlier snapshot of that name. Ranked below `/<id>/...` routes, which
+/// otherwise collide with it.
+#[post("/snapshot/<name>", rank = 2)]
+fn create_snapshot(
+    name: String,
+    todos: State<TodoRepository>,
+    snapshots: State<Snapshots>,
+) -> Result<JsonValue, ApiError> {
+    let copy = todos.read()?.clone();
+    let count = copy.len();
+    snapshots.lock()?.insert(name, copy);
+    Ok(json!({ "status": "ok", "todos": count }))
+}
+
+#[get("/snapshot/<name>", format = "json", rank = 2)]
+fn get_snapshot(
+    name: String,
+    snapshots: State<Snapshots>,
+    settings: State<Settings>,
+) -> Result<Option<JsonValue>, ApiError> {
+    let snapshots = snapshots.lock()?;
+    let snapshot = match snapshots.get(&name) {
+        Some(snapshot) => snapshot,
+        None => return Ok(None),
+    };
+    let mut todos: Vec<&Todo> = snapshot.values().collect();
+    todos.sort_by_key(|todo| todo.id);
+    Ok(Some(json!(views(&todos, &settings))))
+}
+
+/// The simplest uptime check: no store access and no content negotiation.
+#[get("/ping")]
+fn ping() -> &'static str {
+    "pong"
+}
+
+/// How many todos are stored, and roughly how many bytes they take up as
+/// JSON, for capacity planning.
+#[get("/debug/size", format = "json")]
+fn store_size(todos: State<TodoRepository>) -> Result<JsonValue, ApiError> {
+    let hashmap = todos.read()?;
+    let mut approx_bytes = 0;
+    for todo in hashmap.values() {
+        approx_bytes += serde_json::to_vec(todo)?.len();
+    }
+    Ok(json!({ "count": hashmap.len(), "approx_bytes": approx_bytes }))
+}
+
+/// Unlike `/ping`, checks that the write-ahead log, when there is one,
+/// can still be written.
+#[get("/health/deep")]
+fn deep_health(journal: State<Journal>) -> status::Custom<JsonValue> {
+    let probe = match journal.0 {
+        Some(ref wal) => wal.probe(),
+        None => Ok(()),
+    };
+    match probe {
+        Ok(()) => status::Custom(Status::Ok, json!({ "status": "ok", "storage": "ok" })),
+        Err(e) => status::Custom(
+            Status::ServiceUnavailable,
+            json!({
+                "status": "error",
+                "storage": "failed",
+                "reason": format!("Could not write to storage: {}.", e),
+            }),
+        ),
+    }
+}
+
+#[catch(404)]
+fn not_found() -> JsonValue {
+    json!({
+        "status": "error",
+        "reason": "Resource was not found."
+    })
+}
+
+#[catch(400)]
+fn bad_request(request: &Request) -> JsonValue {
+    let reason = match request.local_cache(|| BodyError(None)).0 {
+        Some(ref reason) => reason.clone(),
+        None => "Request could not be understood.".to_string(),
+    };
+    json!({ "status": "error", "reason": reason })
+}
+
+#[catch(503)]
+fn service_unavailable() -> JsonValue {
+    json!({
+        "status": "error",
+        "reason": "Service is read-only."
+    })
+}
+
+/// Old paths permanently redirected to where they moved.
+const ALIASES: &[(&str, &str)] = &[("/todos", "/")];
+
+/// Answers a renamed route with a `308` to its new path, keeping the query.
+#[derive(Clone)]
+struct Alias(&'static str);
+
+impl Handler for Alias {
+    fn handle<'r>(&self, request: &'r Request, _: Data) -> handler::Outcome<'r> {
+        let location = match request.uri().query() {
+            Some(query) => format!("{}?{}", self.0, query),
+            None => self.0.to_string(),
+        };
+        handler::Outcome::from(request, Redirect::permanent(location))
+    }
+}
+
+fn aliases() -> Vec<Route> {
+    ALIASES
+        .iter()
+        .map(|&(from, to)| Route::new(Method::Get, from, Alias(to)))
+        .collect()
+}
+
+/// Routes that can be switched off for staged rollouts, grouped by feature.
+/// Every feature is mounted unless configured off, as with
+/// `features = { bulk = false }`; disabled routes answer `404`.
+fn feature_routes(config: &Config) -> Vec<Route> {
+    let features = config.get_table("features").ok();
+    let enabled = |name: &str| {
+        features
+            .and_then(|features| features.get(name))
+            .and_then(|enabled| enabled.as_bool())
+            .unwrap_or(true)
+    };
+    let mut routes = Vec::new();
+    if enabled("bulk") {
+        routes.extend(routes![bulk_complete, tag_todos, schedule_todos]);
+    }
+    routes
+}
+
+fn rocket() -> rocket::Rocket {
+    build(rocket::ignite())
+}
+
+fn build(rocket: rocket::Rocket) -> rocket::Rocket {
+    let features = feature_routes(rocket.config());
+    rocket
+        .register(catchers![bad_request, not_found, service_unavailable])
+        .mount(
+            "/",
+            routes![
+                index,
+                board,
+                get_single_todo,
+                get_todo_meta,
+                get_todo_field,
+                get_todo_ics,
+                similar_todos,
+                random_todo,
+                top_todos,
+                validate,
+                export_json,
+                upcoming_todos,
+                stale_todos,
+                list_tags,
+                count_by_tag,
+                todo_tree,
+                export_markdown,
+                first_todo,
+                last_todo,
+                diff,
+                add_todo,
+                delete_todo,
+                delete_completed,
+                update_todo,
+                replace_todos,
+                patch_todo,
+                toggle_todo,
+                reset_priority,
+                snooze_todo,
+                rescale_priorities,
+                compact,
+                create_snapshot,
+                get_snapshot,
+                ping,
+                import_json,
+                list_changes,
+                autocomplete,
+                archive_todo,
+                restore_todo,
+                list_archived,
+                purge_archived,
+                workload,
+                graph_dot,
+                oldest_incomplete,
+                duplicate_todos,
+                increment_count,
+                deep_health,
+                reorder_todos,
+                store_size,
+                unscheduled_todos,
+                complete_with_note,
+                todo_ancestors
+            ],
+        )
+        .mount("/", features)
+        .mount("/", aliases())
+        .manage(RwLock::new(HashMap::<ID, DateTime<Utc>>::new()))
+        .manage(Mutex::new(HashMap::<String, HashMap<ID, Todo>>::new()))
+        .manage(Importing(AtomicBool::new(false)))
+        .manage(ChangeLog(Mutex::new(VecDeque::new())))
+        .attach(AdHoc::on_attach("Store", |rocket| {
+            let accept_all = Validator(Box::new(AcceptAll));
+            let validator = rocket.state::<Validator>().unwrap_or(&accept_all);
+            let (todos, journal) = match open_store(rocket.config(), validator) {
+                Ok(store) => store,
+                Err(e) => {
+                    log::warn!("Could not replay the write-ahead log: {}", e);
+                    return Err(rocket);
+                }
+            };
+            let next_id = NextId::after(todos.keys().max().copied());
+            Ok(rocket
+                .manage(RwLock::new(todos))
+                .manage(next_id)
+                .manage(journal))
+        }))
+        .attach(AdHoc::on_attach("Settings", |rocket| {
+            let settings = Settings::from_config(rocket.config());
+            let cache = IndexCache::from_config(rocket.config());
+            let webhook = Webhook::start(settings.webhook_url.clone());
+            let rocket = if settings.jwt_secret.is_some() {
+                rocket.attach(JwtAuth)
+            } else {
+                rocket
+            };
+            Ok(rocket.manage(settings).manage(cache).manage(webhook))
+        }))
+        .attach(AdHoc::on_attach(
+            "Rate limit",
+            |rocket| match RateLimit::from_config(rocket.config()) {
+                Some(rate_limit) => Ok(rocket.attach(rate_limit)),
+                None => Ok(rocket),
+            },
+        ))
+        .attach(AdHoc::on_attach(
+            "Same-origin enforcement",
+            |rocket| match SameOrigin::from_config(rocket.config()) {
+                Some(same_origin) => Ok(rocket.attach(same_origin)),
+                None => Ok(rocket),
+            },
+        ))
+        .attach(AdHoc::on_attach(
+            "Security headers",
+            |rocket| match SecurityHeaders::from_config(rocket.config()) {
+                Some(headers) => Ok(rocket.attach(headers)),
+                None => Ok(rocket),
+            },
+        ))
+        .attach(AdHoc::on_attach("Lenient content type", |rocket| {
+            if rocket
+                .config()
+                .get_bool("lenient_content_type")
+                .unwrap_or(false)
+            {
+                Ok(rocket.attach(LenientContentType))
+            } else {
+                Ok(rocket)
+            }
+        }))
+        .attach(AdHoc::on_attach(
+            "Auto-archive",
+            |rocket| match AutoArchive::from_config(rocket.config()) {
+                Some(auto_archive) => Ok(rocket.attach(auto_archive)),
+                None => Ok(rocket),
+            },
+        ))
+        .attach(AdHoc::on_attach("Dedup window", |rocket| {
+            match rocket.config().get_int("dedup_window_ms") {
+                Ok(ms) if ms > 0 => Ok(rocket.attach(DedupWindow {
+                    window: Duration::from_millis(ms as u64),
+                    seen: Mutex::new(HashMap::new()),
+                })),
+                _ => Ok(rocket),
+            }
+        }))
+        .attach(AdHoc::on_attach("Request log", |rocket| {
+            match rocket.config().get_str("log_format") {
+                Ok("json") => Ok(rocket.attach(JsonLog)),
+                _ => Ok(rocket),
+            }
+        }))
+        .attach(AdHoc::on_attach("Slow request log", |rocket| {
+            let threshold = match rocket.config().get_int("slow_request_ms") {
+                Ok(ms) if ms >= 0 => ms as u64,
+                _ => DEFAULT_SLOW_REQUEST_MS,
+            };
+            Ok(rocket.attach(SlowRequestLog {
+                threshold: Duration::from_millis(threshold),
+            }))
+        }))
+        .attach(Rejections)
+        .attach(PreferReturn)
+        .attach(ExpectContinue)
+        .attach(AdHoc::on_attach("Response envelope", |rocket| {
+            if rocket.config().get_bool("envelope").unwrap_or(false) {
+                Ok(rocket.attach(Envelope))
+            } else {
+                Ok(rocket)
+            }
+        }))
+        .attach(AdHoc::on_attach("camelCase fields", |rocket| match rocket
+            .config()
+            .get_str("field_case")
+        {
+            Ok("camel") => Ok(rocket.attach(CamelCase)),
+            _ => Ok(rocket),
+        }))
+        // Attached after the envelope so the envelope is indented too.
+        .attach(AdHoc::on_attach("Pretty JSON", |rocket| {
+            let by_default = rocket.config().get_bool("pretty_json").unwrap_or(false);
+            Ok(rocket.attach(PrettyJson { by_default }))
+        }))
+        // Attached last of the body rewriters, so it compresses final bodies.
+        .attach(AdHoc::on_attach("Response compression", |rocket| {
+            let level = match rocket.config().get_int("compression_level") {
+                Ok(level) if level >= 0 => level as u32,
+                _ => 6,
+            };
+            Ok(rocket.attach(ResponseCompression { level }))
+        }))
+        .attach(AdHoc::on_attach("Validator", |rocket| {
+            if rocket.state::<Validator>().is_some() {
+                Ok(rocket)
+            } else {
+                Ok(rocket.manage(Validator(Box::new(AcceptAll))))
+            }
+        }))
+}
+
+fn main() {
+    rocket().launch();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::SecondsFormat;
+    use jsonwebtoken::{EncodingKey, Header as JwtHeader};
+    use rocket::config::Environment;
+    use rocket::config::Value;
+    use rocket::http::{Accept, ContentType, Header, Status};
+    use rocket::local::{Client, LocalResponse};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::sync::Arc;
+    use todo::TodoClient;
+
+    fn client_with(extras: &[(&str, Value)]) -> Client {
+        let mut config = Config::development();
+        config.set_extras(
+            extras
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.clone()))
+                .collect(),
+        );
+        Client::new(build(rocket::custom(config))).unwrap()
+    }
+
+    fn json_body(res: &mut LocalResponse) -> serde_json::Value {
+        serde_json::from_str(&res.body_string().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn bad_get_put() {
+        let client = Client::new(rocket()).unwrap();
+
+        // Try to get a message with an ID that doesn't exist.
+        let mut res = client.get("/99").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+
+        let body = res.body_string().unwrap();
+        assert!(body.contains("error"));
+        assert!(body.contains("Todo 99 not found."));
+
+        // Try to get a message with an invalid ID.
+        let mut res = client.get("/hi").header(ContentType::JSON).dispatch();
+        let body = res.body_string().unwrap();
+        assert_eq!(res.status(), Status::NotFound);
+        assert!(body.contains("error"));
+
+        // Try to put a message without a proper body.
+        let res = client.put("/80").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+
+        // Try to put a message for an ID that doesn't exist.
+        let res = client
+            .put("/80")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 80, "title": "todo-1", "priority": 4 }"#)
+            .dispatch();
+
+        assert_eq!(res.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn post_get_put_get() {
+        let client = Client::new(rocket()).unwrap();
+
+        // Check that no todo exist at default
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let body = res.body().unwrap().into_string().unwrap();
+        assert_eq!(body, "[]");
+        // Check that a todo with ID 1 doesn't exist.
+        let res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+
+        // Add a new todo with ID 1.
+        let res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+
+        assert_eq!(res.status(), Status::Ok);
+
+        // Check that the todo exists with the correct contents.
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let body = res.body().unwrap().into_string().unwrap();
+        assert!(body.contains("write tests"));
+
+        // Change the todo.
+        let res = client
+            .put("/1")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests updated", "priority": 3 }"#)
+            .dispatch();
+
+        assert_eq!(res.status(), Status::Ok);
+
+        // Check that the todo exists with the updated contents.
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let body = res.body().unwrap().into_string().unwrap();
+        assert!(!body.contains("Hello, world!"));
+        assert!(body.contains("write tests updated"));
+    }
+
+    #[test]
+    fn conditional_get() {
+        let client = Client::new(rocket()).unwrap();
+
+        let res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+
+        // A plain read reports when the todo was last modified.
+        let res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert!(res.headers().get_one("Last-Modified").is_some());
+
+        // The todo hasn't changed since a date in the future.
+        let future = SystemTime::now() + Duration::from_secs(60 * 60);
+        let since = Header::new("If-Modified-Since", httpdate::fmt_http_date(future));
+        let mut res = client
+            .get("/1")
+            .header(ContentType::JSON)
+            .header(since)
+            .dispatch();
+        assert_eq!(res.status(), Status::NotModified);
+        assert!(res.body_string().is_none());
+
+        // But it has changed since a date in the past.
+        let past = SystemTime::now() - Duration::from_secs(60 * 60);
+        let since = Header::new("If-Modified-Since", httpdate::fmt_http_date(past));
+        let res = client
+            .get("/1")
+            .header(ContentType::JSON)
+            .header(since)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+    }
+
+    #[test]
+    fn dry_run_leaves_store_untouched() {
+        let client = Client::new(rocket()).unwrap();
+
+        // Previewing a create returns the todo that would be stored.
+        let mut res = client
+            .post("/?dry_run=true")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let body = res.body_string().unwrap();
+        assert!(body.contains("dry_run"));
+        assert!(body.contains("write tests"));
+
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        assert_eq!(res.body_string().unwrap(), "[]");
+
+        let res = client
+            .post("/?dry_run=maybe")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+
+        // Previewing an update or delete of a real todo leaves it as it was.
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+
+        let res = client
+            .put("/1?dry_run=true")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write docs", "priority": 2 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+
+        let mut res = client
+            .delete("/1?dry_run=true")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert!(res.body_string().unwrap().contains("write tests"));
+
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert!(res.body_string().unwrap().contains("write tests"));
+    }
+
+    #[test]
+    fn board_partitions_by_completion() {
+        let client = Client::new(rocket()).unwrap();
+
+        for body in &[
+            r#"{ "id": 1, "title": "write tests", "priority": 2 }"#,
+            r#"{ "id": 2, "title": "write docs", "priority": 5, "completed": true }"#,
+            r#"{ "id": 3, "title": "review", "priority": 4 }"#,
+            r#"{ "id": 4, "title": "release", "priority": 1, "completed": true }"#,
+        ] {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(*body)
+                .dispatch();
+        }
+
+        let mut res = client.get("/board").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let body = json_body(&mut res);
+        let ids = |list: &str| -> Vec<u64> {
+            body[list]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|todo| todo["id"].as_u64().unwrap())
+                .collect()
+        };
+        assert_eq!(ids("pending"), vec![3, 1]);
+        assert_eq!(ids("completed"), vec![2, 4]);
+    }
+
+    #[test]
+    fn titles_are_trimmed() {
+        let client = Client::new(rocket()).unwrap();
+
+        let res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "  buy milk ", "priority": 3 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert!(res.body_string().unwrap().contains(r#""title":"buy milk""#));
+
+        let res = client
+            .put("/1")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "\tbuy bread\n", "priority": 3 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert!(res
+            .body_string()
+            .unwrap()
+            .contains(r#""title":"buy bread""#));
+
+        // A title of only whitespace is rejected outright.
+        let mut res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 2, "title": "   ", "priority": 3 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+        assert!(res
+            .body_string()
+            .unwrap()
+            .contains("Title must not be empty."));
+    }
+
+    #[test]
+    fn meta_omits_payload() {
+        let client = Client::new(rocket()).unwrap();
+
+        let mut res = client.get("/1/meta").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+        assert!(res.body_string().unwrap().contains("Todo 1 not found."));
+
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+
+        let mut res = client.get("/1/meta").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let body = json_body(&mut res);
+        assert_eq!(body["id"], 1);
+        assert_eq!(body["version"], 1);
+        assert_eq!(body["etag"], "\"1-1\"");
+        assert!(body.get("created_at").is_some());
+        assert!(body.get("updated_at").is_some());
+        assert!(body.get("title").is_none());
+    }
+
+    #[test]
+    fn put_uses_path_id() {
+        let client = Client::new(rocket()).unwrap();
+
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+
+        let res = client
+            .put("/1")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 7, "title": "write more tests", "priority": 4 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        let body = json_body(&mut res);
+        assert_eq!(body["id"], 1);
+        assert_eq!(body["title"], "write more tests");
+
+        let res = client.get("/7").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn random_picks_pending_todo() {
+        let client = Client::new(rocket()).unwrap();
+
+        let res = client.get("/random").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write docs", "priority": 4, "completed": true }"#)
+            .dispatch();
+
+        // Completed todos are never picked.
+        let res = client.get("/random").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 2, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+
+        for _ in 0..10 {
+            let mut res = client.get("/random").header(ContentType::JSON).dispatch();
+            assert_eq!(res.status(), Status::Ok);
+            assert!(res.body_string().unwrap().contains("write tests"));
+        }
+    }
+
+    #[test]
+    fn malformed_ids_are_bad_requests() {
+        let client = Client::new(rocket()).unwrap();
+
+        let mut res = client.get("/-1").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+        assert!(res.body_string().unwrap().contains("must not be negative"));
+
+        let mut res = client
+            .get("/99999999999999999999999999999999")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+        assert!(res.body_string().unwrap().contains("must not exceed"));
+
+        let res = client.delete("/-1").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+
+        // Ids that are well formed but unknown are still not found.
+        let res = client.get("/99").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn read_only_mode_rejects_writes() {
+        let client = client_with(&[("read_only", Value::Boolean(true))]);
+
+        let mut res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::ServiceUnavailable);
+        assert!(res.body_string().unwrap().contains("Service is read-only."));
+
+        let res = client.delete("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::ServiceUnavailable);
+
+        let res = client.get("/").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+    }
+
+    #[test]
+    fn diff_returns_changes_since() {
+        let client = Client::new(rocket()).unwrap();
+
+        let res = client.get("/diff").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+        let res = client
+            .get("/diff?since=yesterday")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+
+        thread::sleep(Duration::from_millis(5));
+        let since = Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true);
+        thread::sleep(Duration::from_millis(5));
+
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 2, "title": "write docs", "priority": 4 }"#)
+            .dispatch();
+
+        let mut res = client
+            .get(format!("/diff?since={}", since))
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let body = json_body(&mut res);
+        let todos = body["todos"].as_array().unwrap();
+        assert_eq!(todos.len(), 1);
+        assert_eq!(todos[0]["id"], 2);
+        assert!(body["deleted"].as_array().unwrap().is_empty());
+
+        client.delete("/1").header(ContentType::JSON).dispatch();
+
+        let mut res = client
+            .get(format!("/diff?since={}", since))
+            .header(ContentType::JSON)
+            .dispatch();
+        let body = json_body(&mut res);
+        assert_eq!(body["deleted"][0], 1);
+    }
+
+    #[test]
+    fn index_combines_filters() {
+        let client = Client::new(rocket()).unwrap();
+
+        for body in &[
+            r#"{ "id": 1, "title": "Write report", "priority": 4, "tags": ["work"] }"#,
+            r#"{ "id": 2, "title": "write report", "priority": 4, "tags": ["home"] }"#,
+            r#"{ "id": 3, "title": "write report", "priority": 2, "tags": ["work"] }"#,
+            r#"{ "id": 4, "title": "report", "priority": 4, "tags": ["work"], "completed": true }"#,
+            r#"{ "id": 5, "title": "review", "priority": 4, "tags": ["work"] }"#,
+        ] {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(*body)
+                .dispatch();
+        }
+
+        let mut res = client
+            .get("/?priority=4&completed=false&tag=work&q=report")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let body = json_body(&mut res);
+        let todos = body.as_array().unwrap();
+        assert_eq!(todos.len(), 1);
+        assert_eq!(todos[0]["id"], 1);
+
+        // Each filter is validated on its own.
+        let res = client
+            .get("/?priority=9")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+        let res = client
+            .get("/?completed=maybe")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn client_against_running_server() {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .port();
+        let config = Config::build(Environment::Development)
+            .address("127.0.0.1")
+            .port(port)
+            .finalize()
+            .unwrap();
+        thread::spawn(move || build(rocket::custom(config)).launch());
+
+        let client = TodoClient::builder(format!("http://127.0.0.1:{}", port))
+            .timeout(Duration::from_secs(5))
+            .build()
+            .unwrap();
+
+        // Wait for the server to start accepting connections.
+        let mut todos = client.list();
+        for _ in 0..100 {
+            if todos.is_ok() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+            todos = client.list();
+        }
+        assert!(todos.unwrap().is_empty());
+
+        let todo = Todo::new(1, "write tests", Priority(4));
+        assert_eq!(client.create(&todo).unwrap(), 1);
+        let fetched = client.get(1).unwrap().unwrap();
+        assert_eq!(fetched.title, "write tests");
+        assert_eq!(fetched.priority, Priority(4));
+
+        let updated = Todo::new(1, "write more tests", Priority(2));
+        assert!(client.update(1, &updated).unwrap());
+        assert!(!client.update(2, &updated).unwrap());
+        assert_eq!(client.get(1).unwrap().unwrap().title, "write more tests");
+
+        client.delete(1).unwrap();
+        assert!(client.get(1).unwrap().is_none());
+        assert!(client.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn concurrent_creates_get_unique_ids() {
+        let client = Arc::new(Client::new(rocket()).unwrap());
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let client = Arc::clone(&client);
+                thread::spawn(move || {
+                    (0..10)
+                        .map(|_| {
+                            let mut res = client
+                                .post("/")
+                                .header(ContentType::JSON)
+                                .body(r#"{ "title": "write tests", "priority": 4 }"#)
+                                .dispatch();
+                            json_body(&mut res)["id"].as_u64().unwrap()
+                        })
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        let mut ids: Vec<u64> = handles
+            .into_iter()
+            .flat_map(|handle| handle.join().unwrap())
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), 80);
+
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res).as_array().unwrap().len(), 80);
+
+        // Explicit ids are never handed out again.
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 500, "title": "write docs", "priority": 4 }"#)
+            .dispatch();
+        let mut res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "title": "write docs", "priority": 4 }"#)
+            .dispatch();
+        assert_eq!(json_body(&mut res)["id"], 501);
+    }
+
+    #[test]
+    fn bulk_complete_reports_missing() {
+        let client = Client::new(rocket()).unwrap();
+
+        for id in 1..=3 {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(format!(
+                    r#"{{ "id": {}, "title": "write tests", "priority": 4 }}"#,
+                    id
+                ))
+                .dispatch();
+        }
+
+        let mut res = client
+            .patch("/bulk-complete")
+            .header(ContentType::JSON)
+            .body(r#"{ "ids": [1, 3, 8, 9] }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let body = json_body(&mut res);
+        assert_eq!(body["completed"], 2);
+        assert_eq!(body["missing"], serde_json::json!([8, 9]));
+
+        let mut res = client
+            .get("/?completed=true")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(json_body(&mut res).as_array().unwrap().len(), 2);
+        let mut res = client.get("/2").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res)["completed"], false);
+    }
+
+    #[test]
+    fn title_length_counts_characters() {
+        let client = Client::new(rocket()).unwrap();
+
+        let title = "\u{1F600}".repeat(DEFAULT_MAX_TITLE_LENGTH - 1);
+        assert!(title.len() > DEFAULT_MAX_TITLE_LENGTH);
+        let todo = Todo::new(1, title.as_str(), Priority(3));
+        let res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(serde_json::to_string(&todo).unwrap())
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res)["title"], title);
+
+        let todo = Todo::new(2, "x".repeat(DEFAULT_MAX_TITLE_LENGTH + 1), Priority(3));
+        let res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(serde_json::to_string(&todo).unwrap())
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn busy_store_times_out_writes() {
+        let client = Arc::new(client_with(&[("lock_timeout_ms", Value::Integer(50))]));
+
+        // Hold the write lock from another thread until told to let go.
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let holder = {
+            let client = Arc::clone(&client);
+            thread::spawn(move || {
+                let todos = client.rocket().state::<TodoRepository>().unwrap();
+                let _hashmap = todos.write().unwrap();
+                locked_tx.send(()).unwrap();
+                release_rx.recv().unwrap();
+            })
+        };
+        locked_rx.recv().unwrap();
+
+        let started = Instant::now();
+        let mut res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::ServiceUnavailable);
+        assert_eq!(res.headers().get_one("Retry-After"), Some("1"));
+        assert!(res.body_string().unwrap().contains("Store is busy."));
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        release_tx.send(()).unwrap();
+        holder.join().unwrap();
+
+        let res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+    }
+
+    #[test]
+    fn first_and_last_by_id() {
+        let client = Client::new(rocket()).unwrap();
+
+        let res = client.get("/first").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+        let res = client.get("/last").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+
+        for id in &[5, 2, 9] {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(format!(
+                    r#"{{ "id": {}, "title": "write tests", "priority": 4 }}"#,
+                    id
+                ))
+                .dispatch();
+        }
+
+        let mut res = client.get("/first").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res)["id"], 2);
+        let mut res = client.get("/last").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res)["id"], 9);
+    }
+
+    #[test]
+    fn priority_range_is_configurable() {
+        let post = |client: &Client, priority: usize| {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(format!(
+                    r#"{{ "title": "write tests", "priority": {} }}"#,
+                    priority
+                ))
+                .dispatch()
+                .status()
+        };
+
+        let client = Client::new(rocket()).unwrap();
+        assert_eq!(post(&client, 5), Status::Ok);
+        assert_eq!(post(&client, 8), Status::BadRequest);
+        assert_eq!(post(&client, 0), Status::BadRequest);
+
+        let client = client_with(&[
+            ("min_priority", Value::Integer(1)),
+            ("max_priority", Value::Integer(10)),
+        ]);
+        assert_eq!(post(&client, 8), Status::Ok);
+        assert_eq!(post(&client, 11), Status::BadRequest);
+
+        let res = client
+            .get("/?priority=8")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let mut res = client
+            .get("/?priority=11")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+        assert!(res.body_string().unwrap().contains("between 1 and 10"));
+    }
+
+    #[test]
+    fn snooze_pushes_due_date() {
+        let client = Client::new(rocket()).unwrap();
+
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4, "due_date": "2030-01-01T09:00:00Z" }"#)
+            .dispatch();
+
+        let mut res = client
+            .post("/1/snooze")
+            .header(ContentType::JSON)
+            .body(r#"{ "days": 3 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(json_body(&mut res)["due_date"], "2030-01-04T09:00:00Z");
+
+        for days in &["0", "-2"] {
+            let res = client
+                .post("/1/snooze")
+                .header(ContentType::JSON)
+                .body(format!(r#"{{ "days": {} }}"#, days))
+                .dispatch();
+            assert_eq!(res.status(), Status::BadRequest);
+        }
+
+        let res = client
+            .post("/2/snooze")
+            .header(ContentType::JSON)
+            .body(r#"{ "days": 3 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn snooze_rejects_out_of_range_days() {
+        let client = Client::new(rocket()).unwrap();
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+
+        // Too many days for a duration, then too many for a date.
+        for days in &["100000000000", "100000000"] {
+            let res = client
+                .post("/1/snooze")
+                .header(ContentType::JSON)
+                .body(format!(r#"{{ "days": {} }}"#, days))
+                .dispatch();
+            assert_eq!(res.status(), Status::BadRequest);
+        }
+
+        let res = client.get("/").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+    }
+
+    #[test]
+    fn reads_flag_overdue_todos() {
+        let client = Client::new(rocket()).unwrap();
+
+        for body in &[
+            r#"{ "id": 1, "title": "file taxes", "priority": 5, "due_date": "2001-04-15T00:00:00Z" }"#,
+            r#"{ "id": 2, "title": "file taxes", "priority": 5, "due_date": "2001-04-15T00:00:00Z", "completed": true }"#,
+            r#"{ "id": 3, "title": "renew passport", "priority": 5, "due_date": "2999-01-01T00:00:00Z" }"#,
+            r#"{ "id": 4, "title": "write tests", "priority": 5, "is_overdue": true }"#,
+        ] {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(*body)
+                .dispatch();
+        }
+
+        let is_overdue = |id: usize| {
+            let mut res = client
+                .get(format!("/{}", id))
+                .header(ContentType::JSON)
+                .dispatch();
+            json_body(&mut res)["is_overdue"].clone()
+        };
+        assert_eq!(is_overdue(1), true);
+        assert_eq!(is_overdue(2), false);
+        assert_eq!(is_overdue(3), false);
+        assert_eq!(is_overdue(4), false);
+
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        let body = json_body(&mut res);
+        let overdue: Vec<_> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|todo| todo["is_overdue"] == true)
+            .collect();
+        assert_eq!(overdue.len(), 1);
+        assert_eq!(overdue[0]["id"], 1);
+    }
+
+    #[test]
+    fn json_patch_updates_fields() {
+        let client = Client::new(rocket()).unwrap();
+        let json_patch = ContentType::new("application", "json-patch+json");
+
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 2 }"#)
+            .dispatch();
+
+        let mut res = client
+            .patch("/1")
+            .header(json_patch.clone())
+            .body(r#"[{ "op": "replace", "path": "/priority", "value": 5 }]"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(json_body(&mut res)["todo"]["priority"], 5);
+
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        let body = json_body(&mut res);
+        assert_eq!(body["priority"], 5);
+        assert_eq!(body["title"], "write tests");
+
+        // Patches that can't apply, or leave an invalid todo, change nothing.
+        for patch in &[
+            r#"[{ "op": "remove", "path": "/missing" }]"#,
+            r#"[{ "op": "replace", "path": "/priority", "value": 9 }]"#,
+            r#"[{ "op": "replace", "path": "/title", "value": 3 }]"#,
+        ] {
+            let res = client
+                .patch("/1")
+                .header(json_patch.clone())
+                .body(*patch)
+                .dispatch();
+            assert_eq!(res.status(), Status::BadRequest);
+        }
+
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res)["priority"], 5);
+
+        let res = client
+            .patch("/2")
+            .header(json_patch)
+            .body(r#"[{ "op": "replace", "path": "/priority", "value": 5 }]"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn top_returns_highest_priorities() {
+        let client = Client::new(rocket()).unwrap();
+
+        for (id, priority) in &[(1, 2), (2, 5), (3, 1), (4, 4), (5, 3)] {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(format!(
+                    r#"{{ "id": {}, "title": "write tests", "priority": {} }}"#,
+                    id, priority
+                ))
+                .dispatch();
+        }
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 6, "title": "write docs", "priority": 5, "completed": true }"#)
+            .dispatch();
+
+        let mut res = client.get("/top?n=2").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let body = json_body(&mut res);
+        let ids: Vec<_> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|t| t["id"].clone())
+            .collect();
+        assert_eq!(ids, vec![2, 4]);
+
+        let mut res = client.get("/top").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res).as_array().unwrap().len(), 5);
+
+        for n in &["0", "-1", "many"] {
+            let res = client
+                .get(format!("/top?n={}", n))
+                .header(ContentType::JSON)
+                .dispatch();
+            assert_eq!(res.status(), Status::BadRequest);
+        }
+    }
+
+    #[test]
+    fn msgpack_round_trip() {
+        let client = Client::new(rocket()).unwrap();
+
+        let mut todo = Todo::new(1, " write tests ", Priority(4));
+        todo.tags = vec!["work".to_string()];
+        let res = client
+            .post("/")
+            .header(ContentType::MsgPack)
+            .body(rmp_serde::to_vec_named(&todo).unwrap())
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+
+        let mut res = client.get("/1").header(Accept::MsgPack).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(res.content_type(), Some(ContentType::MsgPack));
+        let fetched: Todo = rmp_serde::from_slice(&res.body_bytes().unwrap()).unwrap();
+        assert_eq!(fetched.title, "write tests");
+        assert_eq!(fetched.tags, vec!["work".to_string()]);
+
+        // JSON stays the default representation.
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(res.content_type(), Some(ContentType::JSON));
+        assert_eq!(json_body(&mut res)["title"], "write tests");
+
+        let res = client
+            .post("/")
+            .header(ContentType::MsgPack)
+            .body(&b"not msgpack"[..])
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+    }
+
+    struct NoBannedWords;
+
+    impl TodoValidator for NoBannedWords {
+        fn validate(&self, todo: &Todo) -> Result<(), String> {
+            if todo.title.contains("banned") {
+                Err("Title contains a banned word.".to_string())
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn custom_validator_rejects_todos() {
+        let rocket = rocket::ignite().manage(Validator(Box::new(NoBannedWords)));
+        let client = Client::new(build(rocket)).unwrap();
+
+        let mut res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "a banned title", "priority": 3 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+        assert!(res
+            .body_string()
+            .unwrap()
+            .contains("Title contains a banned word."));
+
+        let res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "a fine title", "priority": 3 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+
+        let res = client
+            .put("/1")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "banned after all", "priority": 3 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn delete_completed_keeps_pending() {
+        let client = Client::new(rocket()).unwrap();
+
+        for id in 1..=4 {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(format!(
+                    r#"{{ "id": {}, "title": "write tests", "priority": 4 }}"#,
+                    id
+                ))
+                .dispatch();
+        }
+        client
+            .patch("/bulk-complete")
+            .header(ContentType::JSON)
+            .body(r#"{ "ids": [1, 3] }"#)
+            .dispatch();
+
+        let mut res = client
+            .delete("/completed")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(json_body(&mut res)["deleted"], 2);
+
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        let body = json_body(&mut res);
+        let mut ids: Vec<_> = body
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|todo| todo["id"].as_u64().unwrap())
+            .collect();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![2, 4]);
+    }
+
+    #[test]
+    fn disallowed_origins_are_forbidden() {
+        let allowed = Value::Array(vec![Value::String("https://todo.example".to_string())]);
+        let client = client_with(&[("allowed_origins", allowed)]);
+
+        let mut res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .header(Header::new("Origin", "https://evil.example"))
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Forbidden);
+        assert!(res
+            .body_string()
+            .unwrap()
+            .contains("Origin is not allowed."));
+
+        // The rejected request never reached the store.
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(res.body_string().unwrap(), "[]");
+
+        let res = client
+            .get("/")
+            .header(ContentType::JSON)
+            .header(Header::new("Origin", "https://todo.example"))
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+    }
+
+    #[test]
+    fn json_pointer_extracts_a_field() {
+        let client = Client::new(rocket()).unwrap();
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+
+        let mut res = client
+            .get("/1/json-pointer/priority")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(res.body_string().unwrap(), "4");
+
+        let res = client
+            .get("/1/json-pointer/nope")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn wal_replays_mutations() {
+        let path = std::env::temp_dir().join(format!("todo-wal-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let wal = wal::Wal::open(&path).unwrap();
+        let mut first = Todo::new(1, "write tests", Priority(4));
+        wal.append(&wal::Entry::put(&first)).unwrap();
+        wal.append(&wal::Entry::put(&Todo::new(2, "ship it", Priority(2))))
+            .unwrap();
+        first.completed = true;
+        wal.append(&wal::Entry::put(&first)).unwrap();
+        wal.append(&wal::Entry::Delete { id: 2 }).unwrap();
+
+        let todos = wal::replay(&path).unwrap();
+        assert_eq!(todos.len(), 1);
+        assert_eq!(todos[&1], first);
+
+        // Compaction keeps the same state in a single entry per todo.
+        wal.compact(todos.values()).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+        assert_eq!(wal::replay(&path).unwrap(), todos);
+
+        let client = client_with(&[("wal_path", Value::String(path.display().to_string()))]);
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert!(res.body_string().unwrap().contains("write tests"));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn tags_are_counted() {
+        let client = Client::new(rocket()).unwrap();
+        let bodies = [
+            r#"{ "title": "standup", "priority": 3, "tags": ["work"] }"#,
+            r#"{ "title": "laundry", "priority": 2, "tags": ["home", "chores"] }"#,
+            r#"{ "title": "review", "priority": 4, "tags": ["work", "chores"] }"#,
+            r#"{ "title": "deploy", "priority": 5, "tags": ["work"] }"#,
+        ];
+        for body in bodies.iter() {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(*body)
+                .dispatch();
+        }
+
+        let mut res = client.get("/tags").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(
+            json_body(&mut res),
+            serde_json::json!([
+                { "tag": "work", "count": 3 },
+                { "tag": "chores", "count": 2 },
+                { "tag": "home", "count": 1 }
+            ])
+        );
+    }
+
+    #[test]
+    fn if_version_guards_updates() {
+        let client = Client::new(rocket()).unwrap();
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+
+        let res = client
+            .put("/1")
+            .header(ContentType::JSON)
+            .header(Header::new("X-If-Version", "1"))
+            .body(r#"{ "title": "write more tests", "priority": 4 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res)["version"], 2);
+
+        let mut res = client
+            .put("/1")
+            .header(ContentType::JSON)
+            .header(Header::new("X-If-Version", "1"))
+            .body(r#"{ "title": "stale edit", "priority": 4 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Conflict);
+        assert!(res.body_string().unwrap().contains("version 2"));
+
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res)["title"], "write more tests");
+    }
+
+    #[test]
+    fn snapshots_are_unaffected_by_later_writes() {
+        let client = Client::new(rocket()).unwrap();
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+
+        let res = client.post("/snapshot/before").dispatch();
+        assert_eq!(res.status(), Status::Ok);
+
+        client
+            .put("/1")
+            .header(ContentType::JSON)
+            .body(r#"{ "title": "rewrite tests", "priority": 4 }"#)
+            .dispatch();
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 2, "title": "ship it", "priority": 2 }"#)
+            .dispatch();
+
+        let mut res = client
+            .get("/snapshot/before")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let snapshot = json_body(&mut res);
+        assert_eq!(snapshot.as_array().unwrap().len(), 1);
+        assert_eq!(snapshot[0]["title"], "write tests");
+
+        let res = client
+            .get("/snapshot/missing")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn log_entries_serialize_as_one_json_line() {
+        let entry = LogEntry {
+            ts: "2024-05-01T12:00:00Z".parse().unwrap(),
+            method: "GET",
+            path: "/1",
+            status: 200,
+            ms: 3,
+        };
+        assert_eq!(
+            entry.to_line().unwrap(),
+            r#"{"ts":"2024-05-01T12:00:00Z","method":"GET","path":"/1","status":200,"ms":3}"#
+        );
+    }
+
+    #[test]
+    fn ping_answers_in_plain_text() {
+        let client = Client::new(rocket()).unwrap();
+        let mut res = client.get("/ping").dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(res.content_type(), Some(ContentType::Plain));
+        assert_eq!(res.body_string(), Some("pong".to_string()));
+    }
+
+    #[test]
+    fn envelope_wraps_successful_responses() {
+        let client = client_with(&[("envelope", Value::Boolean(true))]);
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let body = json_body(&mut res);
+        assert_eq!(body["meta"], serde_json::json!({ "count": 1 }));
+        assert_eq!(body["data"][0]["title"], "write tests");
+
+        let mut res = client.get("/2").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+        assert_eq!(json_body(&mut res)["status"], "error");
+    }
+
+    #[test]
+    fn prefer_return_minimal_drops_the_body() {
+        let client = Client::new(rocket()).unwrap();
+        let mut res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .header(Header::new("Prefer", "return=minimal"))
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::NoContent);
+        assert_eq!(res.body_string(), None);
+
+        let mut res = client
+            .put("/1")
+            .header(ContentType::JSON)
+            .header(Header::new("Prefer", "return=representation"))
+            .body(r#"{ "title": "write more tests", "priority": 4 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(json_body(&mut res)["status"], "ok");
+    }
+
+    #[test]
+    fn colors_must_be_hex() {
+        let client = Client::new(rocket()).unwrap();
+        let res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r##"{ "id": 1, "title": "write tests", "priority": 4, "color": "#ff8800" }"##)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res)["color"], "#ff8800");
+
+        let mut res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 2, "title": "ship it", "priority": 2, "color": "red" }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+        assert!(res.body_string().unwrap().contains("#RRGGBB"));
+    }
+
+    #[test]
+    fn upcoming_lists_todos_due_soon() {
+        let client = Client::new(rocket()).unwrap();
+        let now = Utc::now();
+        let due = |days| (now + chrono::Duration::days(days)).to_rfc3339();
+        let bodies = vec![
+            serde_json::json!({ "id": 1, "title": "tomorrow", "priority": 3, "due_date": due(1) }),
+            serde_json::json!({ "id": 2, "title": "later", "priority": 3, "due_date": due(10) }),
+            serde_json::json!({ "id": 3, "title": "someday", "priority": 3 }),
+        ];
+        for body in bodies {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(body.to_string())
+                .dispatch();
+        }
+
+        let mut res = client
+            .get("/upcoming?days=7")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let upcoming = json_body(&mut res);
+        assert_eq!(upcoming.as_array().unwrap().len(), 1);
+        assert_eq!(upcoming[0]["id"], 1);
+
+        for days in &["0", "9223372036854775807"] {
+            let res = client
+                .get(format!("/upcoming?days={}", days))
+                .header(ContentType::JSON)
+                .dispatch();
+            assert_eq!(res.status(), Status::BadRequest);
+        }
+    }
+
+    #[test]
+    fn max_title_length_is_configurable() {
+        let client = client_with(&[("max_title_length", Value::Integer(10))]);
+        let res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "ten chars!", "priority": 3 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+
+        let mut res = client
+            .put("/1")
+            .header(ContentType::JSON)
+            .body(r#"{ "title": "eleven char", "priority": 3 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+        assert!(res.body_string().unwrap().contains("at most 10 characters"));
+    }
+
+    #[test]
+    fn put_collection_replaces_the_store() {
+        let client = Client::new(rocket()).unwrap();
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+
+        let mut res = client
+            .put("/")
+            .header(ContentType::JSON)
+            .body(
+                r#"[{ "id": 2, "title": "ship it", "priority": 2 },
+                    { "id": 3, "title": "celebrate", "priority": 1 }]"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(json_body(&mut res), serde_json::json!({ "count": 2 }));
+
+        let res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res).as_array().unwrap().len(), 2);
+
+        // An invalid todo rejects the whole replacement.
+        let res = client
+            .put("/")
+            .header(ContentType::JSON)
+            .body(r#"[{ "id": 4, "title": "fine", "priority": 2 }, { "id": 5, "title": " ", "priority": 2 }]"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res).as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn gzip_bodies_are_decompressed() {
+        let client = Client::new(rocket()).unwrap();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(br#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .unwrap();
+        let res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .header(Header::new("Content-Encoding", "gzip"))
+            .body(encoder.finish().unwrap())
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res)["title"], "write tests");
+
+        let res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .header(Header::new("Content-Encoding", "br"))
+            .body("garbage")
+            .dispatch();
+        assert_eq!(res.status(), Status::UnsupportedMediaType);
+    }
+
+    #[test]
+    fn rescale_clamps_to_the_priority_range() {
+        let client = Client::new(rocket()).unwrap();
+        for (id, priority) in [(1, 1), (2, 2), (3, 3)].iter() {
+            let todo = Todo::new(*id, "rescale me", Priority(*priority));
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(serde_json::to_string(&todo).unwrap())
+                .dispatch();
+        }
+
+        let mut res = client
+            .post("/rescale")
+            .header(ContentType::JSON)
+            .body(r#"{ "factor": 2 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(json_body(&mut res)["changed"], 3);
+
+        for (id, priority) in [(1, 2), (2, 4), (3, 5)].iter() {
+            let mut res = client
+                .get(format!("/{}", id))
+                .header(ContentType::JSON)
+                .dispatch();
+            assert_eq!(json_body(&mut res)["priority"], *priority);
+        }
+
+        let res = client
+            .post("/rescale")
+            .header(ContentType::JSON)
+            .body(r#"{ "factor": 0 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn missing_todos_are_named_in_404s() {
+        let client = Client::new(rocket()).unwrap();
+        let mut res = client.get("/42").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+        assert_eq!(json_body(&mut res)["reason"], "Todo 42 not found.");
+
+        let mut res = client
+            .put("/42")
+            .header(ContentType::JSON)
+            .body(r#"{ "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+        assert_eq!(json_body(&mut res)["reason"], "Todo 42 not found.");
+
+        let mut res = client.delete("/42").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+        assert_eq!(json_body(&mut res)["reason"], "Todo 42 not found.");
+
+        let mut res = client.get("/42/nowhere").dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+        assert_eq!(json_body(&mut res)["reason"], "Resource was not found.");
+    }
+
+    #[test]
+    fn redacted_fields_are_left_out_of_responses() {
+        let redacted = Value::Array(vec![Value::String("owner".to_string())]);
+        let client = client_with(&[("redacted_fields", redacted)]);
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4, "owner": "lovelace" }"#)
+            .dispatch();
+
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        let todos = json_body(&mut res);
+        assert_eq!(todos[0]["title"], "write tests");
+        assert!(todos[0].get("owner").is_none());
+
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert!(json_body(&mut res).get("owner").is_none());
+
+        for uri in &["/board", "/first", "/top", "/tree", "/export.json"] {
+            let mut res = client.get(*uri).header(ContentType::JSON).dispatch();
+            assert_eq!(res.status(), Status::Ok);
+            assert!(!res.body_string().unwrap().contains("lovelace"), "{}", uri);
+        }
+        let res = client
+            .get("/1/json-pointer/owner")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+
+        // Writes answer with the same view as reads.
+        let mut res = client.patch("/1/toggle").dispatch();
+        assert!(!res.body_string().unwrap().contains("lovelace"));
+        let mut res = client
+            .patch("/1")
+            .header(ContentType::new("application", "json-patch+json"))
+            .body(r#"[{ "op": "replace", "path": "/owner", "value": "grace" }]"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let body = res.body_string().unwrap();
+        assert!(!body.contains("lovelace") && !body.contains("grace"));
+        let mut res = client
+            .put("/1?dry_run=true")
+            .header(ContentType::JSON)
+            .body(r#"{ "title": "write tests", "priority": 4, "owner": "lovelace" }"#)
+            .dispatch();
+        assert!(!res.body_string().unwrap().contains("lovelace"));
+    }
+
+    #[test]
+    fn count_by_tag_counts_each_todo_once() {
+        let client = Client::new(rocket()).unwrap();
+        let bodies = [
+            r#"{ "title": "standup", "priority": 3, "tags": ["work", "work"] }"#,
+            r#"{ "title": "laundry", "priority": 2, "tags": ["home"] }"#,
+            r#"{ "title": "review", "priority": 4, "tags": ["work", "home"] }"#,
+            r#"{ "title": "deploy", "priority": 5, "tags": ["work"] }"#,
+            r#"{ "title": "nap", "priority": 1 }"#,
+        ];
+        for body in bodies.iter() {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(*body)
+                .dispatch();
+        }
+
+        let mut res = client
+            .get("/count/by-tag")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(
+            json_body(&mut res),
+            serde_json::json!({ "work": 3, "home": 2 })
+        );
+    }
+
+    #[test]
+    fn toggle_flips_completion() {
+        let client = Client::new(rocket()).unwrap();
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+
+        let mut res = client.patch("/1/toggle").dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(json_body(&mut res)["completed"], true);
+
+        let mut res = client.patch("/1/toggle").dispatch();
+        assert_eq!(json_body(&mut res)["completed"], false);
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res)["completed"], false);
+
+        let res = client.patch("/2/toggle").dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn index_cache_is_dropped_on_writes() {
+        let client = client_with(&[("index_cache_ttl_ms", Value::Integer(60_000))]);
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+
+        let first = client
+            .get("/")
+            .header(ContentType::JSON)
+            .dispatch()
+            .body_bytes();
+        let second = client
+            .get("/")
+            .header(ContentType::JSON)
+            .dispatch()
+            .body_bytes();
+        assert_eq!(first, second);
+
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 2, "title": "ship it", "priority": 2 }"#)
+            .dispatch();
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res).as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn todos_export_as_ics_events() {
+        let client = Client::new(rocket()).unwrap();
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4, "due_date": "2024-05-01T12:00:00Z" }"#)
+            .dispatch();
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 2, "title": "someday", "priority": 1 }"#)
+            .dispatch();
+
+        let mut res = client.get("/1/ics").dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(res.content_type(), Some(ContentType::Calendar));
+        assert_eq!(
+            res.headers().get_one("Content-Disposition"),
+            Some("attachment; filename=\"todo-1.ics\"")
+        );
+        let body = res.body_string().unwrap();
+        assert!(body.contains("BEGIN:VEVENT"));
+        assert!(body.contains("SUMMARY:write tests"));
+        assert!(body.contains("DTSTART:20240501T120000Z"));
+
+        let res = client.get("/2/ics").dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn tags_are_added_and_removed_in_bulk() {
+        let client = Client::new(rocket()).unwrap();
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "standup", "priority": 3, "tags": ["old"] }"#)
+            .dispatch();
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 2, "title": "review", "priority": 4, "tags": ["work"] }"#)
+            .dispatch();
+
+        let mut res = client
+            .post("/tag")
+            .header(ContentType::JSON)
+            .body(r#"{ "ids": [1, 2], "add": ["work"], "remove": ["old"] }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(json_body(&mut res)["changed"], 1);
+
+        for id in 1..=2 {
+            let mut res = client
+                .get(format!("/{}", id))
+                .header(ContentType::JSON)
+                .dispatch();
+            assert_eq!(json_body(&mut res)["tags"], serde_json::json!(["work"]));
+        }
+
+        let res = client
+            .post("/tag")
+            .header(ContentType::JSON)
+            .body(r#"{ "ids": [1, 3], "add": ["home"] }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res)["tags"], serde_json::json!(["work"]));
+    }
+
+    #[test]
+    fn expect_continue_decisions() {
+        let limit = 1024;
+        let cases = [
+            (Method::Post, "100-continue", Some(512), Status::Continue),
+            (Method::Put, "100-Continue", None, Status::Continue),
+            (
+                Method::Post,
+                "100-continue",
+                Some(2048),
+                Status::PayloadTooLarge,
+            ),
+            (
+                Method::Post,
+                "something-else",
+                Some(512),
+                Status::ExpectationFailed,
+            ),
+            (Method::Get, "100-continue", None, Status::ExpectationFailed),
+        ];
+        for &(method, expect, length, status) in cases.iter() {
+            assert_eq!(continue_status(method, expect, length, limit), status);
+        }
+
+        // Declared over the default 1 MiB JSON limit.
+        let client = Client::new(rocket()).unwrap();
+        let res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .header(Header::new("Expect", "100-continue"))
+            .header(Header::new("Content-Length", "2000000"))
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::PayloadTooLarge);
+        let res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn tree_nests_children_under_parents() {
+        let client = Client::new(rocket()).unwrap();
+        let bodies = [
+            r#"{ "id": 1, "title": "release", "priority": 5 }"#,
+            r#"{ "id": 2, "title": "write notes", "priority": 3, "parent_id": 1 }"#,
+            r#"{ "id": 3, "title": "tag build", "priority": 4, "parent_id": 1 }"#,
+            r#"{ "id": 4, "title": "nap", "priority": 1 }"#,
+        ];
+        for body in bodies.iter() {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(*body)
+                .dispatch();
+        }
+
+        let mut res = client.get("/tree").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let tree = json_body(&mut res);
+        assert_eq!(tree.as_array().unwrap().len(), 2);
+        assert_eq!(tree[0]["todo"]["id"], 1);
+        let children = tree[0]["children"].as_array().unwrap();
+        assert_eq!(children.len(), 2);
+        assert_eq!(children[0]["todo"]["id"], 2);
+        assert_eq!(children[1]["todo"]["id"], 3);
+        assert_eq!(children[0]["children"], serde_json::json!([]));
+        assert_eq!(tree[1]["todo"]["id"], 4);
+    }
+
+    #[test]
+    fn default_sort_is_configurable() {
+        let client = client_with(&[("default_sort", Value::String("priority".to_string()))]);
+        for (id, priority) in [(1, 2), (2, 5), (3, 3)].iter() {
+            let todo = Todo::new(*id, "sort me", Priority(*priority));
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(serde_json::to_string(&todo).unwrap())
+                .dispatch();
+        }
+
+        let ids = |res: &mut LocalResponse| -> Vec<u64> {
+            json_body(res)
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|todo| todo["id"].as_u64().unwrap())
+                .collect()
+        };
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        assert_eq!(ids(&mut res), vec![2, 3, 1]);
+
+        let mut res = client.get("/?sort=id").header(ContentType::JSON).dispatch();
+        assert_eq!(ids(&mut res), vec![1, 2, 3]);
+
+        let res = client
+            .get("/?sort=nope")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn similar_ranks_by_shared_words() {
+        let client = Client::new(rocket()).unwrap();
+        for (id, title) in [
+            (1, "buy milk"),
+            (2, "call mom"),
+            (3, "buy bread"),
+            (4, "Buy milk later"),
+        ]
+        .iter()
+        {
+            let todo = Todo::new(*id, *title, Priority(3));
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(serde_json::to_string(&todo).unwrap())
+                .dispatch();
+        }
+
+        let mut res = client
+            .get("/1/similar")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let titles: Vec<String> = json_body(&mut res)
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|todo| todo["title"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(titles, vec!["Buy milk later", "buy bread"]);
+    }
+
+    fn token(scope: &str, expires_in: i64) -> String {
+        let claims = Claims {
+            scope: scope.to_string(),
+            exp: (Utc::now().timestamp() + expires_in) as u64,
+        };
+        let key = EncodingKey::from_secret(b"hunter2");
+        jsonwebtoken::encode(&JwtHeader::default(), &claims, &key).unwrap()
+    }
+
+    #[test]
+    fn jwt_scopes_gate_requests() {
+        let client = client_with(&[("jwt_secret", Value::String("hunter2".to_string()))]);
+        let create = |token: &str| {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .header(Header::new("Authorization", format!("Bearer {}", token)))
+                .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+                .dispatch()
+                .status()
+        };
+
+        assert_eq!(create(&token("todo:read", 3600)), Status::Forbidden);
+        assert_eq!(
+            create(&token("todo:read todo:write", -3600)),
+            Status::Unauthorized
+        );
+        assert_eq!(create(&token("todo:read todo:write", 3600)), Status::Ok);
+
+        let res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::Unauthorized);
+        let res = client
+            .get("/1")
+            .header(ContentType::JSON)
+            .header(Header::new(
+                "Authorization",
+                format!("Bearer {}", token("todo:read", 3600)),
+            ))
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+    }
+
+    #[test]
+    fn delete_honors_if_match() {
+        let client = Client::new(rocket()).unwrap();
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+
+        let res = client.get("/1").header(ContentType::JSON).dispatch();
+        let stale = res.headers().get_one("ETag").unwrap().to_string();
+        client
+            .put("/1")
+            .header(ContentType::JSON)
+            .body(r#"{ "title": "write more tests", "priority": 4 }"#)
+            .dispatch();
+
+        let res = client
+            .delete("/1")
+            .header(ContentType::JSON)
+            .header(Header::new("If-Match", stale))
+            .dispatch();
+        assert_eq!(res.status(), Status::PreconditionFailed);
+
+        let res = client.get("/1").header(ContentType::JSON).dispatch();
+        let current = res.headers().get_one("ETag").unwrap().to_string();
+        let res = client
+            .delete("/1")
+            .header(ContentType::JSON)
+            .header(Header::new("If-Match", current))
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn markdown_export_is_a_checklist() {
+        let client = Client::new(rocket()).unwrap();
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4, "completed": true }"#)
+            .dispatch();
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 2, "title": "ship it", "priority": 5 }"#)
+            .dispatch();
+
+        let mut res = client.get("/export/markdown").dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(
+            res.content_type(),
+            Some(ContentType::new("text", "markdown"))
+        );
+        assert_eq!(
+            res.body_string().unwrap(),
+            "- [ ] ship it (priority 5)\n- [x] write tests (priority 4)\n"
+        );
+    }
+
+    #[test]
+    fn webhooks_receive_create_events() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            // Read until the body named by Content-Length has arrived.
+            loop {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request).to_string();
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let length: usize = text
+                        .lines()
+                        .find_map(|line| {
+                            let (name, value) = line.split_once(':')?;
+                            if name.eq_ignore_ascii_case("content-length") {
+                                value.trim().parse().ok()
+                            } else {
+                                None
+                            }
+                        })
+                        .unwrap_or(0);
+                    if request.len() >= end + 4 + length {
+                        tx.send(text[end + 4..].to_string()).unwrap();
+                        break;
+                    }
+                }
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+        });
+
+        let client = client_with(&[("webhook_url", Value::String(url))]);
+        let res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+
+        let body = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let event: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(event["event"], "created");
+        assert_eq!(event["todo"]["title"], "write tests");
+    }
+
+    #[test]
+    fn renamed_routes_redirect_permanently() {
+        let client = Client::new(rocket()).unwrap();
+        let res = client.get("/todos?priority=3").dispatch();
+        assert_eq!(res.status(), Status::PermanentRedirect);
+        assert_eq!(res.headers().get_one("Location"), Some("/?priority=3"));
+
+        let res = client.get("/todos").dispatch();
+        assert_eq!(res.headers().get_one("Location"), Some("/"));
+    }
+
+    #[test]
+    fn strict_fields_rejects_unknown_fields() {
+        let body = r#"{ "id": 1, "title": "x", "priority": 3, "typ0": true }"#;
+        let client = Client::new(rocket()).unwrap();
+        let res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(body)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+
+        let client = client_with(&[("strict_fields", Value::Boolean(true))]);
+        let mut res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(body)
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+        assert_eq!(json_body(&mut res)["reason"], "Unknown field `typ0`.");
+
+        let res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "x", "priority": 3 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+    }
+
+    #[test]
+    fn json_export_serves_byte_ranges() {
+        let client = Client::new(rocket()).unwrap();
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+
+        let mut res = client.get("/export.json").dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(res.headers().get_one("Accept-Ranges"), Some("bytes"));
+        let whole = res.body_bytes().unwrap();
+
+        let mut res = client
+            .get("/export.json")
+            .header(Header::new("Range", "bytes=2-9"))
+            .dispatch();
+        assert_eq!(res.status(), Status::PartialContent);
+        assert_eq!(
+            res.headers().get_one("Content-Range"),
+            Some(format!("bytes 2-9/{}", whole.len()).as_str())
+        );
+        assert_eq!(res.body_bytes().unwrap(), &whole[2..10]);
+
+        let mut res = client
+            .get("/export.json")
+            .header(Header::new("Range", "bytes=-5"))
+            .dispatch();
+        assert_eq!(res.status(), Status::PartialContent);
+        assert_eq!(res.body_bytes().unwrap(), &whole[whole.len() - 5..]);
+
+        let res = client
+            .get("/export.json")
+            .header(Header::new("Range", format!("bytes={}-", whole.len())))
+            .dispatch();
+        assert_eq!(res.status(), Status::RangeNotSatisfiable);
+
+        let mut res = client
+            .get("/export.json")
+            .header(Header::new("Range", format!("bytes=0-{}", usize::MAX)))
+            .dispatch();
+        assert_eq!(res.status(), Status::PartialContent);
+        assert_eq!(res.body_bytes().unwrap(), whole);
+    }
+
+    #[test]
+    fn envelope_leaves_ranged_bodies_alone() {
+        let client = client_with(&[("envelope", Value::Boolean(true))]);
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+
+        // A range has to slice the same bytes a full download gets.
+        let mut res = client.get("/export.json").dispatch();
+        let whole = res.body_bytes().unwrap();
+        assert_eq!(whole[0], b'[');
+        let mut res = client
+            .get("/export.json")
+            .header(Header::new("Range", "bytes=0-4"))
+            .dispatch();
+        assert_eq!(res.body_bytes().unwrap(), &whole[..5]);
+    }
+
+    #[test]
+    fn validate_reports_every_problem_without_storing() {
+        let client = Client::new(rocket()).unwrap();
+        let mut res = client
+            .post("/validate")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "  ", "priority": 9, "color": "red" }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(
+            json_body(&mut res),
+            serde_json::json!({
+                "valid": false,
+                "errors": {
+                    "color": "Color must be of the form `#RRGGBB`.",
+                    "priority": "Priority must be between 1 and 5.",
+                    "title": "Title must not be empty.",
+                },
+            })
+        );
+
+        let mut res = client
+            .post("/validate")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 3 }"#)
+            .dispatch();
+        assert_eq!(json_body(&mut res), serde_json::json!({ "valid": true }));
+
+        let res = client.get("/1").dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn stale_lists_old_pending_todos() {
+        let client = Client::new(rocket()).unwrap();
+        for (id, title) in &[(1, "old"), (2, "new"), (3, "older")] {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(serde_json::json!({ "id": id, "title": title, "priority": 3 }).to_string())
+                .dispatch();
+        }
+        {
+            let todos = client.rocket().state::<TodoRepository>().unwrap();
+            let mut hashmap = todos.write().unwrap();
+            hashmap.get_mut(&1).unwrap().created_at = Utc::now() - chrono::Duration::days(10);
+            hashmap.get_mut(&3).unwrap().created_at = Utc::now() - chrono::Duration::days(20);
+        }
+
+        let mut res = client
+            .get("/stale?days=5")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let stale = json_body(&mut res);
+        let ids: Vec<u64> = stale
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|todo| todo["id"].as_u64().unwrap())
+            .collect();
+        assert_eq!(ids, vec![3, 1]);
+
+        for days in &["0", "9223372036854775807"] {
+            let res = client
+                .get(format!("/stale?days={}", days))
+                .header(ContentType::JSON)
+                .dispatch();
+            assert_eq!(res.status(), Status::BadRequest);
+        }
+    }
+
+    #[test]
+    fn only_requests_over_the_threshold_are_slow() {
+        let threshold = Duration::from_millis(500);
+        assert!(!is_slow(Duration::from_millis(20), threshold));
+        assert!(!is_slow(threshold, threshold));
+        assert!(is_slow(Duration::from_millis(501), threshold));
+    }
+
+    #[test]
+    fn unique_fields_reject_duplicates() {
+        let unique = Value::Array(vec![Value::String("title".to_string())]);
+        let client = client_with(&[("unique_fields", unique)]);
+        let post = |body: &str| {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch()
+                .status()
+        };
+        assert_eq!(
+            post(r#"{ "id": 1, "title": "write tests", "priority": 3 }"#),
+            Status::Ok
+        );
+        assert_eq!(
+            post(r#"{ "id": 2, "title": "ship it", "priority": 3 }"#),
+            Status::Ok
+        );
+
+        let mut res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 3, "title": "write tests", "priority": 5 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Conflict);
+        assert_eq!(
+            json_body(&mut res)["reason"],
+            "Field `title` must be unique, but todo 1 has the same value."
+        );
+
+        // A preview is turned away for the same clash.
+        let res = client
+            .post("/?dry_run=true")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 3, "title": "write tests", "priority": 5 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Conflict);
+
+        let res = client
+            .put("/2")
+            .header(ContentType::JSON)
+            .body(r#"{ "title": "write tests", "priority": 3 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Conflict);
+
+        // A todo may keep its own title.
+        let res = client
+            .put("/1")
+            .header(ContentType::JSON)
+            .body(r#"{ "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+    }
+
+    #[test]
+    fn reset_priority_restores_the_default() {
+        let client = client_with(&[("default_priority", Value::Integer(2))]);
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 5 }"#)
+            .dispatch();
+
+        let mut res = client
+            .post("/1/reset-priority")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(json_body(&mut res)["priority"], 2);
+
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res)["priority"], 2);
+
+        let res = client
+            .post("/2/reset-priority")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn imports_are_swapped_in_whole() {
+        let client = Arc::new(Client::new(rocket()).unwrap());
+        let imported: Vec<serde_json::Value> = (1..=200)
+            .map(|id| serde_json::json!({ "id": id, "title": format!("todo {}", id), "priority": 3 }))
+            .collect();
+        let body = serde_json::Value::Array(imported).to_string();
+
+        let done = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let client = Arc::clone(&client);
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                let mut seen = BTreeSet::new();
+                while !done.load(Ordering::SeqCst) {
+                    let mut res = client.get("/").header(ContentType::JSON).dispatch();
+                    seen.insert(json_body(&mut res).as_array().unwrap().len());
+                }
+                seen
+            })
+        };
+        let mut res = client
+            .post("/import.json")
+            .header(ContentType::JSON)
+            .body(body)
+            .dispatch();
+        done.store(true, Ordering::SeqCst);
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(json_body(&mut res)["count"], 200);
+        let seen = reader.join().unwrap();
+        assert!(seen.iter().all(|&len| len == 0 || len == 200), "{:?}", seen);
+
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res).as_array().unwrap().len(), 200);
+    }
+
+    #[test]
+    fn writes_wait_out_an_import() {
+        let client = Client::new(rocket()).unwrap();
+        let importing = client.rocket().state::<Importing>().unwrap();
+        let run = importing.start().unwrap();
+
+        let res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 3 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::ServiceUnavailable);
+        assert_eq!(res.headers().get_one("Retry-After"), Some("1"));
+        let res = client.get("/").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+
+        drop(run);
+        let res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 3 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+    }
+
+    #[test]
+    fn pages_link_to_their_neighbours() {
+        let client = Client::new(rocket()).unwrap();
+        for id in 1..=5 {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(serde_json::json!({ "id": id, "title": "todo", "priority": 3 }).to_string())
+                .dispatch();
+        }
+
+        let mut res = client
+            .get("/?per_page=2&sort=id")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(
+            res.headers().get_one("Link"),
+            Some(r#"</?per_page=2&sort=id&page=2>; rel="next""#)
+        );
+        assert_eq!(json_body(&mut res)[0]["id"], 1);
+
+        let mut res = client
+            .get("/?per_page=2&page=2")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(
+            res.headers().get_one("Link"),
+            Some(r#"</?per_page=2&page=1>; rel="prev", </?per_page=2&page=3>; rel="next""#)
+        );
+        assert_eq!(json_body(&mut res).as_array().unwrap().len(), 2);
+
+        let mut res = client
+            .get("/?per_page=2&page=3")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(
+            res.headers().get_one("Link"),
+            Some(r#"</?per_page=2&page=2>; rel="prev""#)
+        );
+        assert_eq!(json_body(&mut res).as_array().unwrap().len(), 1);
+
+        let res = client.get("/?page=0").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+        let res = client.get("/").header(ContentType::JSON).dispatch();
+        assert_eq!(res.headers().get_one("Link"), None);
+
+        let mut res = client
+            .get(format!("/?per_page={}", usize::MAX))
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(json_body(&mut res).as_array().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn metadata_round_trips() {
+        let client = Client::new(rocket()).unwrap();
+        let res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(
+                r#"{ "id": 1, "title": "write tests", "priority": 3, "metadata": { "foo": 123 } }"#,
+            )
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(
+            json_body(&mut res)["metadata"],
+            serde_json::json!({ "foo": 123 })
+        );
+
+        let big = serde_json::json!({
+            "id": 2,
+            "title": "too much",
+            "priority": 3,
+            "metadata": { "blob": "x".repeat(MAX_METADATA_BYTES) },
+        });
+        let mut res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(big.to_string())
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+        assert_eq!(
+            json_body(&mut res)["reason"],
+            "Metadata must be at most 4096 bytes of JSON."
+        );
+    }
+
+    #[test]
+    fn poisoned_store_fails_cleanly() {
+        let client = Arc::new(Client::new(rocket()).unwrap());
+        let poisoner = {
+            let client = Arc::clone(&client);
+            thread::spawn(move || {
+                let todos = client.rocket().state::<TodoRepository>().unwrap();
+                let _hashmap = todos.write().unwrap();
+                panic!("dropped the lock mid-change");
+            })
+        };
+        assert!(poisoner.join().is_err());
+
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::InternalServerError);
+        assert_eq!(
+            json_body(&mut res)["reason"],
+            "The store is unavailable after an earlier request failed."
+        );
+        let res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 3 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::InternalServerError);
+        // Routes that don't touch the store keep working.
+        let res = client.get("/ping").dispatch();
+        assert_eq!(res.status(), Status::Ok);
+    }
+
+    #[test]
+    fn changes_filter_by_operation() {
+        let client = Client::new(rocket()).unwrap();
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 3 }"#)
+            .dispatch();
+        client
+            .put("/1")
+            .header(ContentType::JSON)
+            .body(r#"{ "title": "write more tests", "priority": 3 }"#)
+            .dispatch();
+
+        let mut res = client
+            .get("/changes?op=update")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let changes = json_body(&mut res);
+        assert_eq!(changes.as_array().unwrap().len(), 1);
+        assert_eq!(changes[0]["op"], "update");
+        assert_eq!(changes[0]["id"], 1);
+
+        let mut res = client.get("/changes").header(ContentType::JSON).dispatch();
+        let changes = json_body(&mut res);
+        let ops: Vec<&str> = changes
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|change| change["op"].as_str().unwrap())
+            .collect();
+        assert_eq!(ops, vec!["update", "create"]);
+
+        let res = client
+            .get("/changes?op=rename")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn pretty_query_indents_json() {
+        let client = Client::new(rocket()).unwrap();
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 3 }"#)
+            .dispatch();
+
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert!(!res.body_string().unwrap().contains('\n'));
+
+        let mut res = client
+            .get("/1?pretty=true")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let body = res.body_string().unwrap();
+        assert!(body.contains("\n  \"title\": \"write tests\""), "{}", body);
+
+        let client = client_with(&[("pretty_json", Value::Boolean(true))]);
+        let mut res = client
+            .get("/?pretty=false")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.body_string().unwrap(), "[]");
+        let mut res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 3 }"#)
+            .dispatch();
+        assert!(res.body_string().unwrap().contains('\n'));
+    }
+
+    #[test]
+    fn empty_stores_are_seeded() {
+        let path = std::env::temp_dir().join(format!("todo-seed-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"[{ "id": 4, "title": "write tests", "priority": 3 }, { "title": " ship it ", "priority": 5 },
+                { "id": 8, "title": "", "priority": 3 }, { "id": 9, "title": "too urgent", "priority": 11 }]"#,
+        )
+        .unwrap();
+
+        // Seeds that fail validation are left out; the rest are normalized.
+        let client = client_with(&[("seed_path", Value::String(path.display().to_string()))]);
+        let mut res = client.get("/?sort=id").header(ContentType::JSON).dispatch();
+        let todos = json_body(&mut res);
+        assert_eq!(todos.as_array().unwrap().len(), 2);
+        assert_eq!(todos[0]["id"], 4);
+        assert_eq!(todos[1]["id"], 5);
+        assert_eq!(todos[1]["title"], "ship it");
+
+        std::fs::write(&path, "not json").unwrap();
+        let client = client_with(&[("seed_path", Value::String(path.display().to_string()))]);
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res), serde_json::json!([]));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn autocomplete_completes_title_prefixes() {
+        let client = Client::new(rocket()).unwrap();
+        for title in &["write tests", "Write docs", "read book", "write tests"] {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(serde_json::json!({ "title": title, "priority": 3 }).to_string())
+                .dispatch();
+        }
+
+        let mut res = client
+            .get("/autocomplete?prefix=wr&limit=5")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(
+            json_body(&mut res),
+            serde_json::json!(["Write docs", "write tests"])
+        );
+
+        let mut res = client
+            .get("/autocomplete?prefix=WR&limit=1")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(json_body(&mut res), serde_json::json!(["Write docs"]));
+
+        let res = client
+            .get("/autocomplete?prefix=")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn encodings_are_negotiated_by_quality() {
+        assert_eq!(negotiate_encoding("br, gzip"), Some("br"));
+        assert_eq!(negotiate_encoding("gzip, deflate"), Some("gzip"));
+        assert_eq!(negotiate_encoding("br;q=0.5, gzip"), Some("gzip"));
+        assert_eq!(
+            negotiate_encoding("gzip;q=0.2, deflate;q=0.8"),
+            Some("deflate")
+        );
+        assert_eq!(negotiate_encoding("*;q=0.1, br;q=0"), Some("gzip"));
+        assert_eq!(negotiate_encoding("identity"), None);
+        assert_eq!(negotiate_encoding("gzip;q=0"), None);
+    }
+
+    #[test]
+    fn large_responses_are_compressed() {
+        let client = Client::new(rocket()).unwrap();
+        for id in 1..=30 {
+            let body = serde_json::json!({ "id": id, "title": "write tests", "priority": 3 });
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(body.to_string())
+                .dispatch();
+        }
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        let plain = res.body_bytes().unwrap();
+
+        let mut res = client
+            .get("/")
+            .header(ContentType::JSON)
+            .header(Header::new("Accept-Encoding", "br, gzip"))
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(res.headers().get_one("Content-Encoding"), Some("br"));
+        let compressed = res.body_bytes().unwrap();
+        assert!(compressed.len() < plain.len());
+        let mut decompressed = Vec::new();
+        brotli::Decompressor::new(&compressed[..], 4096)
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, plain);
+
+        let mut res = client
+            .get("/")
+            .header(ContentType::JSON)
+            .header(Header::new("Accept-Encoding", "br;q=0.5, gzip"))
+            .dispatch();
+        assert_eq!(res.headers().get_one("Content-Encoding"), Some("gzip"));
+        let mut decompressed = Vec::new();
+        GzDecoder::new(&res.body_bytes().unwrap()[..])
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, plain);
+
+        // Small bodies aren't worth compressing.
+        let res = client
+            .get("/1")
+            .header(ContentType::JSON)
+            .header(Header::new("Accept-Encoding", "gzip"))
+            .dispatch();
+        assert_eq!(res.headers().get_one("Content-Encoding"), None);
+    }
+
+    #[test]
+    fn purged_archives_are_gone_for_good() {
+        let client = Client::new(rocket()).unwrap();
+        for id in 1..=3 {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(serde_json::json!({ "id": id, "title": "todo", "priority": 3 }).to_string())
+                .dispatch();
+        }
+        for id in 1..=2 {
+            let mut res = client
+                .post(format!("/{}/archive", id))
+                .header(ContentType::JSON)
+                .dispatch();
+            assert_eq!(res.status(), Status::Ok);
+            assert!(json_body(&mut res)["archived_at"].is_string());
+        }
+
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res).as_array().unwrap().len(), 1);
+        let mut res = client.get("/archived").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res).as_array().unwrap().len(), 2);
+
+        let mut res = client
+            .delete("/archived")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(json_body(&mut res), serde_json::json!({ "purged": 2 }));
+
+        let mut res = client.get("/archived").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res), serde_json::json!([]));
+        let res = client
+            .post("/1/restore")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+        let res = client.get("/3").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+    }
+
+    #[test]
+    fn workload_totals_each_owner() {
+        let client = Client::new(rocket()).unwrap();
+        let bodies = vec![
+            serde_json::json!({ "title": "a", "priority": 3, "owner": "alice" }),
+            serde_json::json!({ "title": "b", "priority": 5, "owner": "alice" }),
+            serde_json::json!({ "title": "c", "priority": 2, "owner": "alice", "completed": true }),
+            serde_json::json!({ "title": "d", "priority": 1, "owner": "bob" }),
+            serde_json::json!({ "title": "e", "priority": 4 }),
+        ];
+        for body in bodies {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(body.to_string())
+                .dispatch();
+        }
+
+        let mut res = client.get("/workload").header(ContentType::JSON).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(
+            json_body(&mut res),
+            serde_json::json!([
+                { "owner": "alice", "pending": 2, "completed": 1, "total_priority": 8 },
+                { "owner": "bob", "pending": 1, "completed": 0, "total_priority": 1 },
+            ])
+        );
+    }
+
+    #[test]
+    fn tags_are_deduplicated_and_capped() {
+        let client = Client::new(rocket()).unwrap();
+        let tags: Vec<String> = (0..21).map(|i| format!("tag{}", i)).collect();
+        let mut res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(serde_json::json!({ "title": "x", "priority": 3, "tags": tags }).to_string())
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+        assert_eq!(
+            json_body(&mut res)["reason"],
+            "A todo can have at most 20 tags."
+        );
+
+        let res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "x", "priority": 3, "tags": ["a", "b", "a", "b", "c"] }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(
+            json_body(&mut res)["tags"],
+            serde_json::json!(["a", "b", "c"])
+        );
+    }
+
+    #[test]
+    fn graph_dot_links_parents_to_children() {
+        let client = Client::new(rocket()).unwrap();
+        let bodies = vec![
+            serde_json::json!({ "id": 1, "title": "release \"v2\"", "priority": 3 }),
+            serde_json::json!({ "id": 2, "title": "write notes", "priority": 3, "parent_id": 1 }),
+        ];
+        for body in bodies {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(body.to_string())
+                .dispatch();
+        }
+
+        let mut res = client.get("/graph.dot").dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(
+            res.content_type(),
+            Some(ContentType::new("text", "vnd.graphviz"))
+        );
+        assert_eq!(
+            res.body_string().unwrap(),
+            "digraph todos {\n  1 [label=\"release \\\"v2\\\"\"];\n  2 [label=\"write notes\"];\n  1 -> 2;\n}\n"
+        );
+    }
+
+    #[test]
+    fn backpressure_waits_for_room_in_the_bucket() {
+        let max = Duration::from_millis(250);
+        // Room left: no wait.
+        assert_eq!(backpressure(0.0, 5.0, 10.0, max), Duration::from_secs(0));
+        assert_eq!(backpressure(4.0, 5.0, 10.0, max), Duration::from_secs(0));
+        // Full: wait for one request to leak out, then for each queued ahead.
+        assert_eq!(
+            backpressure(5.0, 5.0, 10.0, max),
+            Duration::from_millis(100)
+        );
+        assert_eq!(
+            backpressure(6.0, 5.0, 10.0, max),
+            Duration::from_millis(200)
+        );
+        // Never longer than the cap.
+        assert_eq!(backpressure(50.0, 5.0, 10.0, max), max);
+    }
+
+    #[test]
+    fn rate_limit_rejects_or_delays_bursts() {
+        let client = client_with(&[
+            ("rate_limit_per_sec", Value::Integer(1)),
+            ("rate_limit_burst", Value::Integer(2)),
+        ]);
+        assert_eq!(client.get("/ping").dispatch().status(), Status::Ok);
+        assert_eq!(client.get("/ping").dispatch().status(), Status::Ok);
+        let mut res = client.get("/ping").dispatch();
+        assert_eq!(res.status(), Status::TooManyRequests);
+        assert_eq!(json_body(&mut res)["reason"], "Too many requests.");
+
+        let client = client_with(&[
+            ("rate_limit_per_sec", Value::Integer(100)),
+            ("rate_limit_burst", Value::Integer(1)),
+            ("rate_limit_mode", Value::String("delay".to_string())),
+            ("rate_limit_max_delay_ms", Value::Integer(50)),
+        ]);
+        for _ in 0..3 {
+            assert_eq!(client.get("/ping").dispatch().status(), Status::Ok);
+        }
+    }
+
+    #[test]
+    fn patch_reports_changed_fields() {
+        let client = Client::new(rocket()).unwrap();
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 3 }"#)
+            .dispatch();
+
+        let mut res = client
+            .patch("/1")
+            .header(ContentType::new("application", "json-patch+json"))
+            .body(r#"[{ "op": "replace", "path": "/priority", "value": 5 }]"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let body = json_body(&mut res);
+        assert_eq!(body["todo"]["priority"], 5);
+        assert_eq!(
+            body["changes"],
+            serde_json::json!([{ "field": "priority", "old": 3, "new": 5 }])
+        );
+    }
+
+    #[test]
+    fn security_headers_are_opt_in() {
+        let client = Client::new(rocket()).unwrap();
+        let res = client.get("/ping").dispatch();
+        assert_eq!(res.headers().get_one("Strict-Transport-Security"), None);
+        assert_eq!(res.headers().get_one("X-Content-Type-Options"), None);
+
+        let client = client_with(&[
+            ("hsts", Value::Boolean(true)),
+            ("nosniff", Value::Boolean(true)),
+            ("frame_deny", Value::Boolean(true)),
+        ]);
+        let res = client.get("/").header(ContentType::JSON).dispatch();
+        assert_eq!(
+            res.headers().get_one("Strict-Transport-Security"),
+            Some("max-age=31536000; includeSubDomains")
+        );
+        assert_eq!(
+            res.headers().get_one("X-Content-Type-Options"),
+            Some("nosniff")
+        );
+        assert_eq!(res.headers().get_one("X-Frame-Options"), Some("DENY"));
+    }
+
+    #[test]
+    fn oldest_incomplete_skips_completed_todos() {
+        let client = Client::new(rocket()).unwrap();
+        let res = client
+            .get("/oldest-incomplete")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+
+        for (id, title) in &[(1, "first"), (2, "second"), (3, "third")] {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(serde_json::json!({ "id": id, "title": title, "priority": 3 }).to_string())
+                .dispatch();
+        }
+        client.patch("/1/toggle").dispatch();
+
+        let mut res = client
+            .get("/oldest-incomplete")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(json_body(&mut res)["title"], "second");
+    }
+
+    #[test]
+    fn schedule_sets_due_dates_relative_to_now() {
+        let client = Client::new(rocket()).unwrap();
+        for id in 1..=3 {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(serde_json::json!({ "id": id, "title": "plan", "priority": 3 }).to_string())
+                .dispatch();
+        }
+
+        let before = Utc::now();
+        let mut res = client
+            .post("/schedule")
+            .header(ContentType::JSON)
+            .body(r#"{"ids":[1,2,9],"in_days":7}"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(
+            json_body(&mut res),
+            serde_json::json!({ "updated": 2, "missing": [9] })
+        );
+
+        let todos = client.rocket().state::<TodoRepository>().unwrap();
+        let hashmap = todos.read().unwrap();
+        let week = chrono::Duration::days(7);
+        for id in &[1, 2] {
+            let due_date = hashmap[id].due_date.unwrap();
+            assert!(due_date >= before + week && due_date <= Utc::now() + week);
+        }
+        assert_eq!(hashmap[&1].due_date, hashmap[&2].due_date);
+        assert_eq!(hashmap[&3].due_date, None);
+    }
+
+    #[test]
+    fn field_case_camel_renames_response_keys() {
+        let client = client_with(&[("field_case", Value::String("camel".to_string()))]);
+        let mut res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{"id":1,"title":"camel","priority":3,"metadata":{"source_app":"cli"}}"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        json_body(&mut res);
+
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        let todo = json_body(&mut res);
+        assert!(todo.get("createdAt").is_some());
+        assert!(todo.get("created_at").is_none());
+        assert_eq!(todo["metadata"], serde_json::json!({ "source_app": "cli" }));
+
+        assert_eq!(camel_case("due_date"), "dueDate");
+        assert_eq!(camel_case("_private"), "_private");
+    }
+
+    #[test]
+    fn duplicates_groups_matching_titles() {
+        let client = Client::new(rocket()).unwrap();
+        for (id, title) in &[(1, "Buy Milk"), (2, "walk dog"), (3, " buy milk ")] {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(serde_json::json!({ "id": id, "title": title, "priority": 3 }).to_string())
+                .dispatch();
+        }
+
+        let mut res = client
+            .get("/duplicates")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(
+            json_body(&mut res),
+            serde_json::json!([{ "title": "buy milk", "ids": [1, 3] }])
+        );
+    }
 
-#[derive(Serialize, Deserialize, Copy, Clone)]
-struct Priority(usize);
+    #[test]
+    fn weight_breaks_priority_ties() {
+        let client = Client::new(rocket()).unwrap();
+        for (id, priority, weight) in &[(1, 3, 1.0), (2, 3, 2.0), (3, 4, 0.0), (4, 3, 0.0)] {
+            let todo = serde_json::json!({
+                "id": id,
+                "title": "weighed",
+                "priority": priority,
+                "weight": weight,
+            });
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(todo.to_string())
+                .dispatch();
+        }
 
-impl<'v> FromFormValue<'v> for Priority {
-    type Error = &'v RawStr;
+        let mut res = client
+            .get("/?sort=priority")
+            .header(ContentType::JSON)
+            .dispatch();
+        let ids: Vec<u64> = json_body(&mut res)
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|todo| todo["id"].as_u64().unwrap())
+            .collect();
+        assert_eq!(ids, vec![3, 2, 1, 4]);
 
-    fn from_form_value(form_value: &'v RawStr) -> Result<Priority, &'v RawStr> {
-        match form_value.parse::<usize>() {
-            Ok(data) if data >= 1 && data <= 5 => Ok(Priority(data)),
-            _ => Err(form_value),
-        }
+        let mut todo = Todo::new(0, "unweighable", Priority(3));
+        todo.weight = f64::NAN;
+        let settings = client.rocket().state::<Settings>().unwrap();
+        let validator = Validator(Box::new(AcceptAll));
+        let problems = todo_problems(&mut todo, settings, &validator);
+        assert_eq!(problems[0].0, "weight");
     }
-}
 
-#[derive(Serialize, Deserialize)]
-struct Todo {
-    id: ID,
-    priority: Priority,
-    title: String,
-}
+    #[test]
+    fn increment_bumps_the_count() {
+        let client = Client::new(rocket()).unwrap();
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "snoozed a lot", "priority": 3 }"#)
+            .dispatch();
+
+        for expected in 1..=2 {
+            let mut res = client
+                .post("/1/increment")
+                .header(ContentType::JSON)
+                .dispatch();
+            assert_eq!(res.status(), Status::Ok);
+            assert_eq!(json_body(&mut res)["count"], expected);
+        }
 
-type TodoRepository = Mutex<HashMap<ID, Todo>>;
+        let mut res = client
+            .post("/1/increment")
+            .header(ContentType::JSON)
+            .body(r#"{"by":5}"#)
+            .dispatch();
+        assert_eq!(json_body(&mut res)["count"], 7);
 
-#[get("/", format = "json")]
-fn index(todos: State<TodoRepository>) -> JsonValue {
-    let hashmap = todos.lock().unwrap();
-    let todos_map = hashmap.deref();
-    let mut data: Vec<&Todo> = Vec::new();
+        // A body that doesn't parse is an error, not a missing body.
+        let res = client
+            .post("/1/increment")
+            .header(ContentType::JSON)
+            .body(r#"{"by":"x"}"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res)["count"], 7);
 
-    for (_, v) in todos_map {
-        data.push(v)
+        let res = client
+            .post("/2/increment")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::NotFound);
     }
-    json!(data)
-}
 
-#[get("/<id>", format = "json")]
-fn get_single_todo(id: ID, todos: State<TodoRepository>) -> Option<Json<Todo>> {
-    let hashmap = todos.lock().expect("map locked");
-    hashmap.get(&id).map(|content| {
-        Json(Todo {
-            id: content.id.clone(),
-            title: content.title.clone(),
-            priority: content.priority,
-        })
-    })
-}
+    #[test]
+    fn lenient_content_type_accepts_json_suffixes() {
+        let body = r#"{ "id": 1, "title": "json api", "priority": 3 }"#;
+        let vnd_api = ContentType::new("application", "vnd.api+json");
+        let client = Client::new(rocket()).unwrap();
+        let res = client
+            .post("/")
+            .header(vnd_api.clone())
+            .body(body)
+            .dispatch();
+        assert_ne!(res.status(), Status::Ok);
 
-#[post("/", format = "json", data = "<todo>")]
-fn add_todo(todo: Json<Todo>, todos: State<TodoRepository>) -> JsonValue {
-    let mut hashmap = todos.lock().expect("map locked");
-    hashmap.insert(todo.0.id, todo.0);
-    json!({ "status": "ok" })
-}
+        let client = client_with(&[("lenient_content_type", Value::Boolean(true))]);
+        let res = client.post("/").header(vnd_api).body(body).dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res)["title"], "json api");
+        let res = client
+            .post("/")
+            .body(r#"{ "id": 2, "title": "untyped", "priority": 3 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
 
-#[delete("/<id>", format = "json")]
-fn delete_todo(id: ID, todos: State<TodoRepository>) -> JsonValue {
-    let mut hashmap = todos.lock().expect("map locked");
-    hashmap.remove(&id);
-    json!({ "status": "ok" })
-}
+        // Updates and bulk writes relax the same way as creates.
+        let res = client
+            .put("/1")
+            .header(ContentType::new("application", "vnd.api+json"))
+            .body(r#"{ "title": "json api, renamed", "priority": 3 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let res = client
+            .put("/2")
+            .body(r#"{ "title": "still untyped", "priority": 3 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let res = client
+            .post("/tag")
+            .body(r#"{ "ids": [1, 2], "add": ["api"] }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        let todo = json_body(&mut res);
+        assert_eq!(todo["title"], "json api, renamed");
+        assert_eq!(todo["tags"], serde_json::json!(["api"]));
 
-#[put("/<id>", format = "json", data = "<todo>")]
-fn update_todo(id: ID, todo: Json<Todo>, todos: State<TodoRepository>) -> Option<JsonValue> {
-    let mut hashmap = todos.lock().expect("map locked");
-    if hashmap.contains_key(&id) {
-        hashmap.insert(id, todo.0);
-        Some(json!({ "status": "ok" }))
-    } else {
-        None
+        let mut res = client
+            .post("/")
+            .header(ContentType::Plain)
+            .body(body)
+            .dispatch();
+        assert_eq!(res.status(), Status::UnsupportedMediaType);
+        assert_eq!(
+            json_body(&mut res)["reason"],
+            "Request bodies must be JSON."
+        );
     }
-}
 
-#[catch(404)]
-fn not_found() -> JsonValue {
-    json!({
-        "status": "error",
-        "reason": "Resource was not found."
-    })
-}
+    #[test]
+    fn deep_health_checks_storage_is_writable() {
+        let client = Client::new(rocket()).unwrap();
+        let mut res = client.get("/health/deep").dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(
+            json_body(&mut res),
+            serde_json::json!({ "status": "ok", "storage": "ok" })
+        );
 
-fn rocket() -> rocket::Rocket {
-    rocket::ignite()
-        .register(catchers![not_found])
-        .mount(
-            "/",
-            routes![index, get_single_todo, add_todo, delete_todo, update_todo],
-        )
-        .manage(Mutex::new(HashMap::<ID, Todo>::new()))
-}
+        let dir = std::env::temp_dir().join(format!("todo-health-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("wal.jsonl");
+        let client = client_with(&[("wal_path", Value::String(path.display().to_string()))]);
+        let res = client.get("/health/deep").dispatch();
+        assert_eq!(res.status(), Status::Ok);
 
-fn main() {
-    rocket().launch();
-}
+        // The log stays open, but nothing new can be written beside it.
+        std::fs::remove_dir_all(&dir).unwrap();
+        let mut res = client.get("/health/deep").dispatch();
+        assert_eq!(res.status(), Status::ServiceUnavailable);
+        assert_eq!(json_body(&mut res)["storage"], "failed");
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rocket::http::{ContentType, Status};
-    use rocket::local::Client;
+    #[test]
+    fn if_range_serves_the_range_only_while_unchanged() {
+        let client = Client::new(rocket()).unwrap();
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
+            .dispatch();
+        let res = client.get("/export.json").dispatch();
+        let etag = res.headers().get_one("ETag").unwrap().to_string();
+
+        let res = client
+            .get("/export.json")
+            .header(Header::new("Range", "bytes=0-9"))
+            .header(Header::new("If-Range", etag.clone()))
+            .dispatch();
+        assert_eq!(res.status(), Status::PartialContent);
+
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 2, "title": "ship it", "priority": 2 }"#)
+            .dispatch();
+        let mut res = client
+            .get("/export.json")
+            .header(Header::new("Range", "bytes=0-9"))
+            .header(Header::new("If-Range", etag.clone()))
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_ne!(res.headers().get_one("ETag"), Some(etag.as_str()));
+        assert_eq!(json_body(&mut res).as_array().unwrap().len(), 2);
+    }
 
     #[test]
-    fn bad_get_put() {
+    fn disabled_features_are_not_mounted() {
+        let bulk_complete = |client: &Client| {
+            client
+                .patch("/bulk-complete")
+                .header(ContentType::JSON)
+                .body(r#"{"ids":[]}"#)
+                .dispatch()
+                .status()
+        };
         let client = Client::new(rocket()).unwrap();
+        assert_eq!(bulk_complete(&client), Status::Ok);
 
-        // Try to get a message with an ID that doesn't exist.
-        let mut res = client.get("/99").header(ContentType::JSON).dispatch();
+        let mut features = BTreeMap::new();
+        features.insert("bulk".to_string(), Value::Boolean(false));
+        let client = client_with(&[("features", Value::Table(features))]);
+        assert_eq!(bulk_complete(&client), Status::NotFound);
+        let res = client
+            .post("/tag")
+            .header(ContentType::JSON)
+            .body(r#"{"ids":[],"add":["x"]}"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+        let res = client
+            .post("/bulk")
+            .header(ContentType::JSON)
+            .body("{}")
+            .dispatch();
         assert_eq!(res.status(), Status::NotFound);
+        let res = client.get("/ping").dispatch();
+        assert_eq!(res.status(), Status::Ok);
+    }
 
-        let body = res.body_string().unwrap();
-        assert!(body.contains("error"));
-        assert!(body.contains("Resource was not found."));
+    #[test]
+    fn reorder_assigns_positions_in_the_given_order() {
+        let client = Client::new(rocket()).unwrap();
+        for id in 1..=4 {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(
+                    serde_json::json!({ "id": id, "title": "drag me", "priority": 3 }).to_string(),
+                )
+                .dispatch();
+        }
+        let listed = |client: &Client| -> Vec<u64> {
+            let mut res = client
+                .get("/?sort=position")
+                .header(ContentType::JSON)
+                .dispatch();
+            json_body(&mut res)
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|todo| todo["id"].as_u64().unwrap())
+                .collect()
+        };
 
-        // Try to get a message with an invalid ID.
-        let mut res = client.get("/hi").header(ContentType::JSON).dispatch();
-        let body = res.body_string().unwrap();
-        assert_eq!(res.status(), Status::NotFound);
-        assert!(body.contains("error"));
+        let mut res = client
+            .post("/reorder")
+            .header(ContentType::JSON)
+            .body(r#"{"order":[3,9,1,3]}"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        assert_eq!(
+            json_body(&mut res)["order"],
+            serde_json::json!([3, 1, 2, 4])
+        );
+        assert_eq!(listed(&client), vec![3, 1, 2, 4]);
 
-        // Try to put a message without a proper body.
-        let res = client.put("/80").header(ContentType::JSON).dispatch();
+        client
+            .post("/reorder")
+            .header(ContentType::JSON)
+            .body(r#"{"order":[4]}"#)
+            .dispatch();
+        assert_eq!(listed(&client), vec![4, 3, 1, 2]);
+    }
+
+    #[test]
+    fn dedup_window_answers_double_submissions_once() {
+        let client = client_with(&[("dedup_window_ms", Value::Integer(60_000))]);
+        let body = r#"{ "title": "double click", "priority": 3 }"#;
+        let post = |body: &'static str| {
+            let mut res = client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch();
+            assert_eq!(res.status(), Status::Ok);
+            json_body(&mut res)
+        };
+
+        let first = post(body);
+        assert_eq!(post(body), first);
+        let todos = client.rocket().state::<TodoRepository>().unwrap();
+        assert_eq!(todos.read().unwrap().len(), 1);
+
+        post(r#"{ "title": "another", "priority": 3 }"#);
+        assert_eq!(todos.read().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn due_dates_default_by_priority() {
+        let mut due_in_days = BTreeMap::new();
+        due_in_days.insert("5".to_string(), Value::Integer(1));
+        let client = client_with(&[("due_in_days", Value::Table(due_in_days))]);
+        let before = Utc::now();
+        for (id, priority) in &[(1, 5), (2, 1)] {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(
+                    serde_json::json!({ "id": id, "title": "urgent", "priority": priority })
+                        .to_string(),
+                )
+                .dispatch();
+        }
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 3, "title": "dated", "priority": 5, "due_date": "2030-01-01T00:00:00Z" }"#)
+            .dispatch();
+
+        let todos = client.rocket().state::<TodoRepository>().unwrap();
+        let hashmap = todos.read().unwrap();
+        let day = chrono::Duration::days(1);
+        let due_date = hashmap[&1].due_date.unwrap();
+        assert!(due_date >= before + day && due_date <= Utc::now() + day);
+        assert_eq!(hashmap[&2].due_date, None);
+        assert_eq!(
+            hashmap[&3].due_date.unwrap().to_rfc3339(),
+            "2030-01-01T00:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn debug_size_estimates_the_store() {
+        let client = Client::new(rocket()).unwrap();
+        let size = |client: &Client| {
+            let mut res = client
+                .get("/debug/size")
+                .header(ContentType::JSON)
+                .dispatch();
+            assert_eq!(res.status(), Status::Ok);
+            json_body(&mut res)
+        };
+        assert_eq!(
+            size(&client),
+            serde_json::json!({ "count": 0, "approx_bytes": 0 })
+        );
+
+        for id in 1..=3 {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(
+                    serde_json::json!({ "id": id, "title": "weigh me", "priority": 3 }).to_string(),
+                )
+                .dispatch();
+        }
+        let stats = size(&client);
+        assert_eq!(stats["count"], 3);
+        assert!(stats["approx_bytes"].as_u64().unwrap() > 0);
+    }
+
+    #[test]
+    fn title_prefix_is_required_when_configured() {
+        let client = client_with(&[("title_prefix", Value::String("[work]".to_string()))]);
+        let mut res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "groceries", "priority": 3 }"#)
+            .dispatch();
         assert_eq!(res.status(), Status::BadRequest);
+        assert_eq!(
+            json_body(&mut res)["reason"],
+            "Title must start with `[work]`."
+        );
 
-        // Try to put a message for an ID that doesn't exist.
         let res = client
-            .put("/80")
+            .post("/")
             .header(ContentType::JSON)
-            .body(r#"{ "id": 80, "title": "todo-1", "priority": 4 }"#)
+            .body(r#"{ "id": 1, "title": "[work] quarterly report", "priority": 3 }"#)
             .dispatch();
+        assert_eq!(res.status(), Status::Ok);
 
-        assert_eq!(res.status(), Status::NotFound);
+        let res = client
+            .put("/1")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "quarterly report", "priority": 3 }"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::BadRequest);
     }
 
     #[test]
-    fn post_get_put_get() {
+    fn unscheduled_lists_pending_todos_without_due_dates() {
         let client = Client::new(rocket()).unwrap();
+        for body in &[
+            r#"{ "id": 1, "title": "dated", "priority": 5, "due_date": "2030-01-01T00:00:00Z" }"#,
+            r#"{ "id": 2, "title": "undated", "priority": 2 }"#,
+            r#"{ "id": 3, "title": "done", "priority": 4, "completed": true }"#,
+            r#"{ "id": 4, "title": "urgent", "priority": 4 }"#,
+        ] {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(*body)
+                .dispatch();
+        }
 
-        // Check that no todo exist at default
-        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        let mut res = client
+            .get("/unscheduled")
+            .header(ContentType::JSON)
+            .dispatch();
         assert_eq!(res.status(), Status::Ok);
-        let body = res.body().unwrap().into_string().unwrap();
-        assert_eq!(body, "[]");
-        // Check that a todo with ID 1 doesn't exist.
-        let res = client.get("/1").header(ContentType::JSON).dispatch();
-        assert_eq!(res.status(), Status::NotFound);
+        let ids: Vec<u64> = json_body(&mut res)
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|todo| todo["id"].as_u64().unwrap())
+            .collect();
+        assert_eq!(ids, vec![4, 2]);
+    }
+
+    #[test]
+    fn complete_with_note_records_how() {
+        let client = Client::new(rocket()).unwrap();
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "rotate keys", "priority": 4, "notes": ["started"] }"#)
+            .dispatch();
+
+        let mut res = client
+            .post("/1/complete-with-note")
+            .header(ContentType::JSON)
+            .body(r#"{"note":"done via script"}"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::Ok);
+        let todo = json_body(&mut res);
+        assert_eq!(todo["completed"], true);
+        assert_eq!(
+            todo["notes"],
+            serde_json::json!(["started", "done via script"])
+        );
 
-        // Add a new todo with ID 1.
         let res = client
+            .post("/2/complete-with-note")
+            .header(ContentType::JSON)
+            .body(r#"{"note":"done"}"#)
+            .dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn negotiated_responses_name_what_they_vary_on() {
+        let client = Client::new(rocket()).unwrap();
+        client
             .post("/")
             .header(ContentType::JSON)
             .body(r#"{ "id": 1, "title": "write tests", "priority": 4 }"#)
             .dispatch();
 
-        assert_eq!(res.status(), Status::Ok);
+        let res = client.get("/1").header(Accept::MsgPack).dispatch();
+        assert_eq!(res.content_type(), Some(ContentType::MsgPack));
+        assert_eq!(
+            res.headers().get_one("Vary"),
+            Some("Accept, Accept-Encoding")
+        );
 
-        // Check that the todo exists with the correct contents.
-        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        let res = client.get("/1").header(ContentType::JSON).dispatch();
+        assert_eq!(
+            res.headers().get_one("Vary"),
+            Some("Accept, Accept-Encoding")
+        );
+
+        let res = client.get("/ping").dispatch();
+        assert_eq!(res.headers().get_one("Vary"), Some("Accept-Encoding"));
+    }
+
+    #[test]
+    fn archive_pass_archives_long_completed_todos() {
+        let now = Utc::now();
+        let mut todos = HashMap::new();
+        for (id, completed, days_ago) in &[(1, true, 40), (2, true, 5), (3, false, 40)] {
+            let mut todo = Todo::new(*id, "chore", Priority(3));
+            todo.completed = *completed;
+            todo.updated_at = now - chrono::Duration::days(*days_ago);
+            track_completion(&mut todo, None);
+            todos.insert(*id, todo);
+        }
+
+        let mut logged = Vec::new();
+        let archived = archive_completed(&mut todos, now - chrono::Duration::days(30), |todo| {
+            logged.push(todo.id);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(archived, 1);
+        assert_eq!(logged, vec![1]);
+        assert!(todos[&1].archived_at.is_some());
+        assert!(todos[&2].archived_at.is_none());
+        assert!(todos[&3].archived_at.is_none());
+
+        let client = Client::new(rocket()).unwrap();
+        client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(r#"{ "id": 1, "title": "chore", "priority": 3 }"#)
+            .dispatch();
+        let mut res = client.patch("/1/toggle").dispatch();
+        assert!(json_body(&mut res)["completed_at"].is_string());
+        let mut res = client.patch("/1/toggle").dispatch();
+        assert!(json_body(&mut res)["completed_at"].is_null());
+    }
+
+    #[test]
+    fn auto_archive_runs_on_the_first_request() {
+        let client = client_with(&[("auto_archive_days", Value::Integer(30))]);
+        {
+            let mut todo = Todo::new(1, "chore", Priority(3));
+            todo.completed = true;
+            todo.completed_at = Some(Utc::now() - chrono::Duration::days(40));
+            let todos = client.rocket().state::<TodoRepository>().unwrap();
+            todos.write().unwrap().insert(1, todo);
+        }
+
+        let mut res = client.get("/archived").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res)[0]["id"], 1);
+
+        // A cutoff too far back turns archiving off rather than breaking
+        // the store.
+        let client = client_with(&[("auto_archive_days", Value::Integer(100_000_000))]);
+        let res = client.get("/").header(ContentType::JSON).dispatch();
         assert_eq!(res.status(), Status::Ok);
-        let body = res.body().unwrap().into_string().unwrap();
-        assert!(body.contains("write tests"));
+    }
 
-        // Change the todo.
+    #[test]
+    fn ancestors_run_from_the_closest_parent_to_the_root() {
+        let client = Client::new(rocket()).unwrap();
+        for body in &[
+            r#"{ "id": 1, "title": "launch", "priority": 5 }"#,
+            r#"{ "id": 2, "title": "build", "priority": 4, "parent_id": 1 }"#,
+            r#"{ "id": 3, "title": "test", "priority": 3, "parent_id": 2 }"#,
+        ] {
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(*body)
+                .dispatch();
+        }
+        let ancestors = |id: u64| -> Vec<u64> {
+            let mut res = client
+                .get(format!("/{}/ancestors", id))
+                .header(ContentType::JSON)
+                .dispatch();
+            assert_eq!(res.status(), Status::Ok);
+            json_body(&mut res)
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|todo| todo["id"].as_u64().unwrap())
+                .collect()
+        };
+
+        assert_eq!(ancestors(3), vec![2, 1]);
+        assert_eq!(ancestors(1), Vec::<u64>::new());
         let res = client
-            .put("/1")
+            .get("/9/ancestors")
+            .header(ContentType::JSON)
+            .dispatch();
+        assert_eq!(res.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn archived_todos_are_left_out_of_listings() {
+        let client = Client::new(rocket()).unwrap();
+        for (id, tag) in &[(1, "kept"), (2, "shelved")] {
+            let todo =
+                serde_json::json!({ "id": id, "title": "tidy", "priority": 3, "tags": [tag] });
+            client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(todo.to_string())
+                .dispatch();
+        }
+        client
+            .post("/2/archive")
             .header(ContentType::JSON)
-            .body(r#"{ "id": 1, "title": "write tests updated", "priority": 3 }"#)
             .dispatch();
 
+        let ids = |todos: &serde_json::Value| -> Vec<u64> {
+            todos
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|todo| todo["id"].as_u64().unwrap())
+                .collect()
+        };
+        let mut res = client.get("/board").header(ContentType::JSON).dispatch();
+        assert_eq!(ids(&json_body(&mut res)["pending"]), vec![1]);
+        let mut res = client.get("/tags").header(ContentType::JSON).dispatch();
+        assert!(!json_body(&mut res).to_string().contains("shelved"));
+        let mut res = client.get("/export.json").dispatch();
+        assert_eq!(ids(&json_body(&mut res)), vec![1]);
+    }
+
+    #[test]
+    fn largest_id_is_accepted() {
+        let client = Client::new(rocket()).unwrap();
+        let todo = serde_json::json!({ "id": usize::MAX, "title": "tidy", "priority": 3 });
+        let res = client
+            .post("/")
+            .header(ContentType::JSON)
+            .body(todo.to_string())
+            .dispatch();
         assert_eq!(res.status(), Status::Ok);
 
-        // Check that the todo exists with the updated contents.
-        let mut res = client.get("/1").header(ContentType::JSON).dispatch();
+        // With the last id taken there are none left to hand out, and the
+        // todo holding it is never overwritten.
+        for _ in 0..2 {
+            let res = client
+                .post("/")
+                .header(ContentType::JSON)
+                .body(r#"{ "title": "another", "priority": 3 }"#)
+                .dispatch();
+            assert_eq!(res.status(), Status::InsufficientStorage);
+        }
+
+        let mut res = client
+            .get(format!("/{}", usize::MAX))
+            .header(ContentType::JSON)
+            .dispatch();
         assert_eq!(res.status(), Status::Ok);
-        let body = res.body().unwrap().into_string().unwrap();
-        assert!(!body.contains("Hello, world!"));
-        assert!(body.contains("write tests updated"));
+        assert_eq!(json_body(&mut res)["title"], "tidy");
+        let mut res = client.get("/").header(ContentType::JSON).dispatch();
+        assert_eq!(json_body(&mut res).as_array().unwrap().len(), 1);
     }
 }
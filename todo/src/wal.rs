@@ -0,0 +1,121 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use todo::{Todo, ID};
+
+/// One mutation of the store, written as a line of JSON.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Entry<'a> {
+    Put { todo: Cow<'a, Todo> },
+    Delete { id: ID },
+}
+
+impl<'a> Entry<'a> {
+    pub fn put(todo: &'a Todo) -> Entry<'a> {
+        Entry::Put {
+            todo: Cow::Borrowed(todo),
+        }
+    }
+}
+
+/// An append-only log of store mutations. Replaying it rebuilds the store
+/// after a crash. Replay ignores a torn final line, so the log stays usable
+/// even if a panic cut an append short.
+pub struct Wal {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn write_entry(file: &mut File, entry: &Entry) -> io::Result<()> {
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    file.write_all(line.as_bytes())
+}
+
+impl Wal {
+    /// Opens the log at `path` for appending, creating it if needed.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Wal> {
+        let path = path.into();
+        let file = open_append(&path)?;
+        Ok(Wal {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Durably appends `entry`; callers log a mutation before applying it.
+    pub fn append(&self, entry: &Entry) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        write_entry(&mut file, entry)?;
+        file.sync_data()
+    }
+
+    /// Rewrites the log as one `put` per todo, dropping superseded entries.
+    /// The snapshot is written beside the log and renamed over it, so a crash
+    /// mid-way leaves the old log intact.
+    pub fn compact<'a>(&self, todos: impl Iterator<Item = &'a Todo>) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        let snapshot_path = self.path.with_extension("compact");
+        let mut snapshot = File::create(&snapshot_path)?;
+        for todo in todos {
+            write_entry(&mut snapshot, &Entry::put(todo))?;
+        }
+        snapshot.sync_all()?;
+        fs::rename(&snapshot_path, &self.path)?;
+        *file = open_append(&self.path)?;
+        Ok(())
+    }
+
+    /// Checks the log's directory still takes writes, by writing, reading
+    /// back and removing a scratch file beside the log.
+    pub fn probe(&self) -> io::Result<()> {
+        const PROBE: &[u8] = b"probe";
+        let probe_path = self.path.with_extension("probe");
+        fs::write(&probe_path, PROBE)?;
+        let read = fs::read(&probe_path);
+        fs::remove_file(&probe_path)?;
+        if read? != PROBE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "probe read back differently",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Rebuilds the store from the log at `path`. A missing log is an empty
+/// store, and a torn final line from a crash mid-append is ignored.
+pub fn replay(path: &Path) -> io::Result<HashMap<ID, Todo>> {
+    let mut todos = HashMap::new();
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(todos),
+        Err(e) => return Err(e),
+    };
+
+    let lines = BufReader::new(file)
+        .lines()
+        .collect::<io::Result<Vec<_>>>()?;
+    for (i, line) in lines.iter().enumerate() {
+        match serde_json::from_str(line) {
+            Ok(Entry::Put { todo }) => {
+                todos.insert(todo.id, todo.into_owned());
+            }
+            Ok(Entry::Delete { id }) => {
+                todos.remove(&id);
+            }
+            Err(_) if i + 1 == lines.len() => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(todos)
+}